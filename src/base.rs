@@ -0,0 +1,756 @@
+use anyhow::{bail, Context, Error, Result};
+use std::{fmt, str};
+
+/// How to pick the commit to diff against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseStrategy {
+    /// the merge base of HEAD and the parent branch
+    MergeBase,
+    /// the commit HEAD forked from, according to the parent branch's reflog
+    ForkPoint,
+    /// the merge base of HEAD and its upstream branch
+    Upstream,
+    /// the root commit of HEAD
+    Root,
+    /// the parent branch itself
+    Exact,
+    /// the empty tree, so every line counts as added
+    Empty,
+}
+
+impl str::FromStr for BaseStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge-base" => Ok(Self::MergeBase),
+            "fork-point" => Ok(Self::ForkPoint),
+            "upstream" => Ok(Self::Upstream),
+            "root" => Ok(Self::Root),
+            "exact" => Ok(Self::Exact),
+            "empty" => Ok(Self::Empty),
+            s => bail!("{:?} is not a valid base strategy", s),
+        }
+    }
+}
+
+impl fmt::Display for BaseStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::MergeBase => "merge-base",
+            Self::ForkPoint => "fork-point",
+            Self::Upstream => "upstream",
+            Self::Root => "root",
+            Self::Exact => "exact",
+            Self::Empty => "empty",
+        })
+    }
+}
+
+/// What to diff against when the base commit can't be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// the tip of HEAD's upstream branch
+    Upstream,
+    /// the tip of the remote's default branch
+    RemoteDefault,
+    /// the empty tree, so every line counts as added
+    Empty,
+}
+
+impl str::FromStr for Fallback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upstream" => Ok(Self::Upstream),
+            "remote-default" => Ok(Self::RemoteDefault),
+            "empty" => Ok(Self::Empty),
+            s => bail!("{:?} is not a valid fallback base", s),
+        }
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Upstream => "upstream",
+            Self::RemoteDefault => "remote-default",
+            Self::Empty => "empty",
+        })
+    }
+}
+
+/// A comma-separated list of fallback bases, tried in order, or "none".
+#[derive(Debug)]
+pub struct Fallbacks(pub Vec<Fallback>);
+
+impl Default for Fallbacks {
+    /// Falls back to the upstream branch and then the remote's default
+    /// branch, but never to the empty tree, which would report every line of
+    /// the branch.
+    fn default() -> Self {
+        Self(vec![Fallback::Upstream, Fallback::RemoteDefault])
+    }
+}
+
+impl str::FromStr for Fallbacks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "none" {
+            return Ok(Self(Vec::new()));
+        }
+        let fallbacks = s
+            .split(',')
+            .map(|fallback| fallback.trim().parse())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self(fallbacks))
+    }
+}
+
+/// The error for when HEAD shares no history with what it's compared against,
+/// such as on an orphan branch, which is the only time fallback bases are
+/// used.
+#[derive(Debug)]
+pub struct NoBaseError(&'static str);
+
+impl fmt::Display for NoBaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for NoBaseError {}
+
+/// Resolves the first fallback base that can be resolved.
+///
+/// The empty fallback resolves to no commit, standing for the empty tree.
+pub fn resolve_fallback<'r>(
+    repo: &'r git2::Repository,
+    fallbacks: &Fallbacks,
+) -> Result<(Fallback, Option<git2::Commit<'r>>)> {
+    for &fallback in &fallbacks.0 {
+        let commit = match fallback {
+            Fallback::Upstream => upstream_commit(repo).map(Some),
+            Fallback::RemoteDefault => remote_default_commit(repo).map(Some),
+            Fallback::Empty => Ok(None),
+        };
+        match commit {
+            Ok(commit) => return Ok((fallback, commit)),
+            Err(error) => {
+                debug!("{} fallback base failed: {:#}", fallback, error)
+            },
+        }
+    }
+    bail!("no fallback base could be resolved")
+}
+
+/// The ID of the empty tree, which is diffed against in place of a missing
+/// base commit.
+pub fn empty_tree_id() -> git2::Oid {
+    git2::Oid::hash_object(git2::ObjectType::Tree, &[])
+        .expect("error hashing empty tree")
+}
+
+/// Like [`resolve`] without a direct base reference, but falling back to the
+/// first fallback base that resolves when HEAD has no merge base or root
+/// commit, giving the name of the strategy or fallback used.
+///
+/// Other errors, such as a parent branch that doesn't exist, are returned as
+/// they are.
+pub fn resolve_or_fall_back<'r>(
+    repo: &'r git2::Repository,
+    strategy: Option<BaseStrategy>,
+    parent_branch_name: Option<&str>,
+    fallbacks: &Fallbacks,
+) -> Result<(String, Option<git2::Commit<'r>>)> {
+    match resolve(repo, strategy, parent_branch_name, None) {
+        Ok((strategy, base_commit)) => Ok((strategy.to_string(), base_commit)),
+        Err(error)
+            if !fallbacks.0.is_empty()
+                && error.downcast_ref::<NoBaseError>().is_some() =>
+        {
+            let (fallback, base_commit) = resolve_fallback(repo, fallbacks)
+                .with_context(|| format!("{:#}", error))?;
+            eprintln!(
+                "warning: {:#}, falling back to {} base",
+                error, fallback
+            );
+            Ok((format!("{} fallback", fallback), base_commit))
+        },
+        Err(error) => Err(error),
+    }
+}
+
+/// Resolves the commit to diff against, or `None` for the empty tree, along
+/// with the strategy that picked it.
+///
+/// A direct base reference is always used as-is. Otherwise, with no strategy
+/// given, this is the merge base with the parent branch, or the root commit if
+/// HEAD is on the root branch.
+pub fn resolve<'r>(
+    repo: &'r git2::Repository,
+    strategy: Option<BaseStrategy>,
+    parent_branch_name: Option<&str>,
+    base_commit_ref: Option<&str>,
+) -> Result<(BaseStrategy, Option<git2::Commit<'r>>)> {
+    if let Some(base_commit_ref) = base_commit_ref {
+        if !matches!(strategy, None | Some(BaseStrategy::Exact)) {
+            bail!(
+                "a direct diff base can only be used with the exact strategy"
+            );
+        }
+        debug!("using direct base reference");
+        let base_commit = repo
+            .resolve_reference_from_short_name(base_commit_ref)
+            .and_then(|reference| reference.peel_to_commit())
+            .context("error resolving base commit")?;
+        return Ok((BaseStrategy::Exact, Some(base_commit)));
+    }
+    if strategy == Some(BaseStrategy::Empty) {
+        debug!("diffing against the empty tree");
+        return Ok((BaseStrategy::Empty, None));
+    }
+
+    let head_commit = repo
+        .head()
+        .and_then(|reference| reference.peel_to_commit())
+        .context("error resolving head commit")?;
+    debug!("HEAD commit: {}", head_commit.id());
+
+    let strategy = match strategy {
+        Some(strategy) => strategy,
+        None => {
+            let strategy =
+                implicit_strategy(repo, &head_commit, parent_branch_name)?;
+            debug!("implicitly using {} strategy", strategy);
+            strategy
+        },
+    };
+
+    let base_commit = match strategy {
+        BaseStrategy::MergeBase => {
+            let parent_commit = parent_commit(repo, parent_branch_name)?;
+            debug!("parent commit: {}", parent_commit.id());
+            merge_base(repo, &head_commit, &parent_commit)
+        },
+        BaseStrategy::ForkPoint => {
+            let parent_reference = parent_reference(repo, parent_branch_name)?;
+            let parent_ref_name = parent_reference
+                .name()
+                .context("parent reference name is not valid utf8")?;
+            let reflog = repo
+                .reflog(parent_ref_name)
+                .context("error reading parent reflog")?;
+            // the most recent reflog entry that HEAD descends from is where
+            // HEAD forked from the parent
+            for entry in reflog.iter() {
+                let id = entry.id_new();
+                if id == head_commit.id()
+                    || repo.graph_descendant_of(head_commit.id(), id)?
+                {
+                    debug!("found fork point in parent reflog");
+                    return repo
+                        .find_commit(id)
+                        .map(|commit| (strategy, Some(commit)))
+                        .context("error finding fork point commit");
+                }
+            }
+            debug!("no fork point in parent reflog, using merge base");
+            let parent_commit = parent_reference
+                .peel_to_commit()
+                .context("error resolving parent commit")?;
+            merge_base(repo, &head_commit, &parent_commit)
+        },
+        BaseStrategy::Upstream => {
+            let upstream_commit = upstream_commit(repo)?;
+            merge_base(repo, &head_commit, &upstream_commit)
+        },
+        BaseStrategy::Root => root_commit(repo),
+        BaseStrategy::Exact => parent_commit(repo, parent_branch_name),
+        BaseStrategy::Empty => unreachable!("empty strategy resolved early"),
+    }?;
+    Ok((strategy, Some(base_commit)))
+}
+
+/// Picks a strategy when none is given.
+///
+/// This is the merge base with the parent branch, unless HEAD is on the root
+/// branch, in which case the merge base would be HEAD itself.
+fn implicit_strategy(
+    repo: &git2::Repository,
+    head_commit: &git2::Commit<'_>,
+    parent_branch_name: Option<&str>,
+) -> Result<BaseStrategy> {
+    let root_branch_head_commit = root_branch(repo)?
+        .peel_to_commit()
+        .context("error resolving root branch commit")?;
+    let parent_commit = parent_commit(repo, parent_branch_name)?;
+    if head_commit.id() == parent_commit.id() {
+        if head_commit.id() == root_branch_head_commit.id() {
+            debug!("HEAD is on root branch");
+            Ok(BaseStrategy::Root)
+        } else {
+            bail!("HEAD and parent refs are the same")
+        }
+    } else {
+        Ok(BaseStrategy::MergeBase)
+    }
+}
+
+/// Resolves the tip of HEAD's upstream branch.
+fn upstream_commit(repo: &git2::Repository) -> Result<git2::Commit<'_>> {
+    upstream_reference(repo)?
+        .peel_to_commit()
+        .context("error resolving upstream commit")
+}
+
+fn upstream_reference(repo: &git2::Repository) -> Result<git2::Reference<'_>> {
+    let head = repo.head().context("error resolving HEAD")?;
+    if !head.is_branch() {
+        bail!("HEAD is not a branch, so it has no upstream");
+    }
+    let head_ref_name = head.name().context("HEAD name is not valid utf8")?;
+    let upstream_name = repo
+        .branch_upstream_name(head_ref_name)
+        .context("error resolving upstream branch")?;
+    let upstream_name = upstream_name
+        .as_str()
+        .context("upstream branch name is not valid utf8")?;
+    debug!("upstream branch: {}", upstream_name);
+    repo.find_reference(upstream_name)
+        .context("error resolving upstream branch")
+}
+
+/// Finds the branch HEAD's branch tracks, if it tracks a different branch
+/// than its own copy on a remote, which would make the merge base just the
+/// last push.
+fn tracked_parent_reference(
+    repo: &git2::Repository,
+) -> Option<git2::Reference<'_>> {
+    let head = repo.head().ok()?;
+    let branch_name = head.shorthand()?;
+    let merge_ref_name = repo
+        .config()
+        .ok()?
+        .get_string(&format!("branch.{}.merge", branch_name))
+        .ok()?;
+    if merge_ref_name == format!("refs/heads/{}", branch_name) {
+        return None;
+    }
+    match upstream_reference(repo) {
+        Ok(reference) => Some(reference),
+        Err(error) => {
+            debug!("not using upstream as parent: {:#}", error);
+            None
+        },
+    }
+}
+
+/// Resolves the tip of the default branch of the origin remote, as recorded
+/// by its `HEAD` when it was cloned.
+fn remote_default_commit(repo: &git2::Repository) -> Result<git2::Commit<'_>> {
+    repo.find_reference("refs/remotes/origin/HEAD")
+        .and_then(|reference| reference.resolve())
+        .and_then(|reference| reference.peel_to_commit())
+        .context("error resolving remote default branch")
+}
+
+/// Finds the repository's default branch, trying local branches before
+/// remote-tracking ones.
+fn root_branch(repo: &git2::Repository) -> Result<git2::Reference<'_>> {
+    let mut branch_names = vec!["master".to_owned(), "main".to_owned()];
+    if let Ok(default_branch) = repo
+        .config()
+        .and_then(|config| config.get_string("init.defaultBranch"))
+    {
+        if !branch_names.contains(&default_branch) {
+            branch_names.push(default_branch);
+        }
+    }
+    let local_branch = branch_names.iter().find_map(|name| {
+        repo.find_reference(&format!("refs/heads/{}", name)).ok()
+    });
+    if let Some(reference) = local_branch {
+        return Ok(reference);
+    }
+    if let Ok(reference) = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .and_then(|reference| reference.resolve())
+    {
+        debug!("using origin/HEAD as root branch");
+        return Ok(reference);
+    }
+    branch_names
+        .iter()
+        .find_map(|name| {
+            repo.find_reference(&format!("refs/remotes/origin/{}", name))
+                .ok()
+        })
+        .context("root branch not found")
+}
+
+/// Resolves the tip of the parent branch.
+pub fn parent_commit<'r>(
+    repo: &'r git2::Repository,
+    parent_branch_name: Option<&str>,
+) -> Result<git2::Commit<'r>> {
+    parent_reference(repo, parent_branch_name)?
+        .peel_to_commit()
+        .context("error resolving parent commit")
+}
+
+fn parent_reference<'r>(
+    repo: &'r git2::Repository,
+    parent_branch_name: Option<&str>,
+) -> Result<git2::Reference<'r>> {
+    if let Some(parent_branch_name) = parent_branch_name {
+        repo.find_reference(&format!("refs/heads/{}", parent_branch_name))
+            .context("error resolving parent branch")
+    } else if let Some(reference) = tracked_parent_reference(repo) {
+        debug!("using upstream branch as parent");
+        Ok(reference)
+    } else {
+        root_branch(repo)
+    }
+}
+
+/// Resolves a remote-tracking branch given as REMOTE/BRANCH, fetching it from
+/// the remote first if it isn't known locally.
+pub fn remote_branch_commit<'r>(
+    repo: &'r git2::Repository,
+    remote_branch_name: &str,
+) -> Result<git2::Commit<'r>> {
+    let ref_name = format!("refs/remotes/{}", remote_branch_name);
+    if repo.find_reference(&ref_name).is_err() {
+        let (remote_name, branch_name) = remote_branch_name
+            .split_once('/')
+            .context("remote branch must be given as REMOTE/BRANCH")?;
+        debug!("fetching {} from {}", branch_name, remote_name);
+        let mut remote = repo
+            .find_remote(remote_name)
+            .context("error finding remote")?;
+        let refspec = format!("refs/heads/{}:{}", branch_name, ref_name);
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(remote_callbacks(repo)?);
+        remote
+            .fetch(&[refspec], Some(&mut fetch_options), None)
+            .context("error fetching remote branch")?;
+    }
+    repo.find_reference(&ref_name)
+        .and_then(|reference| reference.peel_to_commit())
+        .context("error resolving remote branch")
+}
+
+/// Builds callbacks which authenticate to remotes the way git would, with keys
+/// from the SSH agent, the configured credential helper, or the platform's
+/// default credentials.
+fn remote_callbacks(
+    repo: &git2::Repository,
+) -> Result<git2::RemoteCallbacks<'static>> {
+    let config = repo.config().context("error reading git config")?;
+    // libgit2 asks again after each rejected credential, so each kind is only
+    // tried once to avoid asking forever
+    let mut tried = git2::CredentialType::empty();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let mut try_kind = |kind| {
+            let untried = allowed.contains(kind) && !tried.contains(kind);
+            if untried {
+                tried.insert(kind);
+            }
+            untried
+        };
+        // SSH URLs without a user name, such as github.com:owner/repo,
+        // conventionally use "git"
+        if try_kind(git2::CredentialType::USERNAME) {
+            git2::Cred::username(username.unwrap_or("git"))
+        } else if try_kind(git2::CredentialType::SSH_KEY) {
+            git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if try_kind(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            git2::Cred::credential_helper(&config, url, username)
+        } else if try_kind(git2::CredentialType::DEFAULT) {
+            git2::Cred::default()
+        } else {
+            Err(git2::Error::from_str("no credentials left to try"))
+        }
+    });
+    Ok(callbacks)
+}
+
+/// Collects the path globs that the branch's commits narrow scans to with
+/// `Grep-Paths:` lines in their messages.
+///
+/// Commits reachable from any of the base commits aren't part of the branch,
+/// so their directives are ignored.
+pub fn scope_globs(
+    repo: &git2::Repository,
+    head_id: git2::Oid,
+    base_commits: &[Option<git2::Commit<'_>>],
+) -> Result<Vec<String>> {
+    let mut revwalk = repo.revwalk().context("error walking branch commits")?;
+    revwalk.push(head_id)?;
+    for base_commit in base_commits.iter().flatten() {
+        revwalk.hide(base_commit.id())?;
+    }
+    let mut globs = Vec::new();
+    for id in revwalk {
+        let commit = repo
+            .find_commit(id?)
+            .context("error finding branch commit")?;
+        let message = match commit.message() {
+            Some(message) => message,
+            None => continue,
+        };
+        for line in message.lines() {
+            let value = match line.split_once(':') {
+                Some((key, value))
+                    if key.trim().eq_ignore_ascii_case("Grep-Paths") =>
+                {
+                    value
+                },
+                _ => continue,
+            };
+            globs.extend(
+                value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|glob| !glob.is_empty())
+                    .map(str::to_owned),
+            );
+        }
+    }
+    globs.sort();
+    globs.dedup();
+    Ok(globs)
+}
+
+pub fn merge_base<'r>(
+    repo: &'r git2::Repository,
+    head_commit: &git2::Commit<'_>,
+    parent_commit: &git2::Commit<'_>,
+) -> Result<git2::Commit<'r>> {
+    let merge_base_id = match repo
+        .merge_base(head_commit.id(), parent_commit.id())
+    {
+        Ok(id) => id,
+        Err(error) if error.code() == git2::ErrorCode::NotFound => {
+            return Err(NoBaseError("HEAD has no merge base with its parent"))
+                .context("error getting merge base commit");
+        },
+        Err(error) => {
+            return Err(error).context("error getting merge base commit")
+        },
+    };
+    repo.find_commit(merge_base_id)
+        .context("error finding merge base commit")
+}
+
+fn root_commit(repo: &git2::Repository) -> Result<git2::Commit<'_>> {
+    let root_commit = repo
+        .revwalk()
+        .and_then(|mut revwalk| {
+            revwalk.push_head()?;
+            revwalk
+                .find_map(|id| {
+                    (|| {
+                        let id = id?;
+                        let commit = repo.find_commit(id)?;
+                        if commit.parent_count() == 0 {
+                            return Ok(Some(commit));
+                        }
+                        Ok(None)
+                    })()
+                    .transpose()
+                })
+                .transpose()
+        })
+        .context("error finding root commit")?
+        .ok_or(NoBaseError("HEAD has no root commit"))?;
+    Ok(root_commit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fallbacks_in_order() {
+        let Fallbacks(fallbacks) =
+            "empty, upstream,remote-default".parse().unwrap();
+        assert_eq!(
+            fallbacks,
+            [Fallback::Empty, Fallback::Upstream, Fallback::RemoteDefault]
+        );
+    }
+
+    #[test]
+    fn parses_none_as_no_fallbacks() {
+        let Fallbacks(fallbacks) = "none".parse().unwrap();
+        assert!(fallbacks.is_empty());
+    }
+
+    #[test]
+    fn defaults_to_upstream_then_remote_default() {
+        assert_eq!(
+            Fallbacks::default().0,
+            [Fallback::Upstream, Fallback::RemoteDefault]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fallbacks() {
+        assert!("".parse::<Fallbacks>().is_err());
+        assert!("upstream,".parse::<Fallbacks>().is_err());
+        assert!("upstream,origin".parse::<Fallbacks>().is_err());
+        assert!("Upstream".parse::<Fallbacks>().is_err());
+    }
+
+    #[test]
+    fn displays_fallbacks_as_parsed() {
+        for name in ["upstream", "remote-default", "empty"] {
+            assert_eq!(name.parse::<Fallback>().unwrap().to_string(), name);
+        }
+    }
+
+    /// A new repository in a temporary directory, removed when dropped.
+    struct TestRepo {
+        path: std::path::PathBuf,
+        repo: git2::Repository,
+    }
+
+    impl TestRepo {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "git-branch-grep-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            let repo = git2::Repository::init(&path).unwrap();
+            Self { path, repo }
+        }
+
+        /// Commits an empty tree to a branch, with the given parents.
+        fn commit(&self, branch: &str, parents: &[git2::Oid]) -> git2::Oid {
+            let signature =
+                git2::Signature::now("Test", "test@example.com").unwrap();
+            let tree_id = self.repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = self.repo.find_tree(tree_id).unwrap();
+            let parents = parents
+                .iter()
+                .map(|&id| self.repo.find_commit(id).unwrap())
+                .collect::<Vec<_>>();
+            self.repo
+                .commit(
+                    Some(&format!("refs/heads/{}", branch)),
+                    &signature,
+                    &signature,
+                    branch,
+                    &tree,
+                    &parents.iter().collect::<Vec<_>>(),
+                )
+                .unwrap()
+        }
+
+        fn checkout(&self, branch: &str) {
+            self.repo
+                .set_head(&format!("refs/heads/{}", branch))
+                .unwrap();
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn empty_fallback() -> Fallbacks {
+        Fallbacks(vec![Fallback::Empty])
+    }
+
+    #[test]
+    fn resolves_the_merge_base_without_falling_back() {
+        let test = TestRepo::new("merge-base");
+        let root_id = test.commit("master", &[]);
+        test.commit("feature", &[root_id]);
+        test.checkout("feature");
+        let (strategy, base_commit) =
+            resolve_or_fall_back(&test.repo, None, None, &empty_fallback())
+                .unwrap();
+        assert_eq!(strategy, "merge-base");
+        assert_eq!(base_commit.map(|commit| commit.id()), Some(root_id));
+    }
+
+    #[test]
+    fn falls_back_on_orphan_branches() {
+        let test = TestRepo::new("orphan");
+        test.commit("master", &[]);
+        test.commit("pages", &[]);
+        test.checkout("pages");
+        let (strategy, base_commit) =
+            resolve_or_fall_back(&test.repo, None, None, &empty_fallback())
+                .unwrap();
+        assert_eq!(strategy, "empty fallback");
+        assert!(base_commit.is_none());
+
+        let error =
+            resolve_or_fall_back(&test.repo, None, None, &Fallbacks(vec![]))
+                .unwrap_err();
+        assert!(error.downcast_ref::<NoBaseError>().is_some());
+    }
+
+    #[test]
+    fn does_not_fall_back_from_a_missing_parent_branch() {
+        let test = TestRepo::new("bad-parent");
+        let root_id = test.commit("master", &[]);
+        test.commit("feature", &[root_id]);
+        test.checkout("feature");
+        let error = resolve_or_fall_back(
+            &test.repo,
+            None,
+            Some("mistyped"),
+            &empty_fallback(),
+        )
+        .unwrap_err();
+        assert!(error.downcast_ref::<NoBaseError>().is_none());
+        assert!(format!("{:#}", error).contains("error resolving parent"));
+    }
+
+    #[test]
+    fn uses_the_merge_base_on_the_root_branch_when_asked() {
+        let test = TestRepo::new("root-merge-base");
+        let root_id = test.commit("master", &[]);
+        let head_id = test.commit("master", &[root_id]);
+        test.checkout("master");
+        let (strategy, base_commit) =
+            resolve(&test.repo, None, None, None).unwrap();
+        assert_eq!(strategy, BaseStrategy::Root);
+        assert_eq!(base_commit.map(|commit| commit.id()), Some(root_id));
+        let (strategy, base_commit) =
+            resolve(&test.repo, Some(BaseStrategy::MergeBase), None, None)
+                .unwrap();
+        assert_eq!(strategy, BaseStrategy::MergeBase);
+        assert_eq!(base_commit.map(|commit| commit.id()), Some(head_id));
+    }
+
+    #[test]
+    fn does_not_fall_back_when_head_is_its_parent() {
+        let test = TestRepo::new("same-parent");
+        let root_id = test.commit("master", &[]);
+        let feature_id = test.commit("feature", &[root_id]);
+        test.repo
+            .branch("copy", &test.repo.find_commit(feature_id).unwrap(), false)
+            .unwrap();
+        test.checkout("feature");
+        let error = resolve_or_fall_back(
+            &test.repo,
+            None,
+            Some("copy"),
+            &empty_fallback(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("are the same"));
+    }
+}
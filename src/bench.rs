@@ -0,0 +1,250 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// The sizes of the synthetic repository and how often to run against it.
+pub struct Options {
+    pub files: usize,
+    pub lines: usize,
+    pub changed_files: usize,
+    pub changed_lines: usize,
+    pub runs: usize,
+    pub keep: bool,
+}
+
+/// The phases timed by --timings, in the order they're printed.
+const PHASES: [&str; 4] =
+    ["commit resolution", "diff", "process diff", "line print"];
+
+/// The pattern each configuration searches the synthetic repository for.
+const PATTERN: &str = "TODO";
+
+/// Templates for the synthetic source lines, a few of which match PATTERN.
+const TEMPLATES: [&str; 8] = [
+    "    let value_{} = compute(input, {});",
+    "    // TODO: handle the {} case before {}",
+    "    if count > {} {{ return Err(Error::Overflow({})); }}",
+    "    for item in items.iter().skip({}).take({}) {{",
+    "    }}",
+    "    log::debug!(\"step {} of {}\");",
+    "    total += weights[{}] * scale_{};",
+    "fn helper_{}(x: u32) -> u32 {{ x * {} }}",
+];
+
+/// Generates a synthetic repository, runs this executable against it with
+/// each configuration, and prints the median time of each phase.
+pub fn run(options: &Options) -> Result<()> {
+    if options.files == 0 || options.runs == 0 {
+        bail!("bench needs at least one file and one run");
+    }
+    if options.changed_files > options.files {
+        bail!(
+            "cannot change {} files in a repository of {}",
+            options.changed_files,
+            options.files
+        );
+    }
+
+    let path = std::env::temp_dir()
+        .join(format!("git-branch-grep-bench-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let result =
+        generate(&path, options).and_then(|()| measure(&path, options));
+    if options.keep {
+        eprintln!("kept synthetic repository at {}", path.display());
+    } else {
+        fs::remove_dir_all(&path)
+            .with_context(|| format!("error removing {}", path.display()))?;
+    }
+    result
+}
+
+fn line(file: usize, line: usize, salt: usize) -> String {
+    let template = TEMPLATES[(file * 31 + line * 17 + salt) % TEMPLATES.len()];
+    template
+        .replacen("{}", &(file + line).to_string(), 1)
+        .replacen("{}", &(line * 7 + salt).to_string(), 1)
+        .replace("{{", "{")
+        .replace("}}", "}")
+}
+
+fn file_path(file: usize) -> PathBuf {
+    Path::new("src")
+        .join(format!("module_{}", file % 16))
+        .join(format!("file_{}.rs", file))
+}
+
+fn write_file(workdir: &Path, file: usize, lines: &[String]) -> Result<()> {
+    let path = workdir.join(file_path(file));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("error creating {}", parent.display()))?;
+    }
+    fs::write(&path, lines.join("\n") + "\n")
+        .with_context(|| format!("error writing {}", path.display()))
+}
+
+fn commit(
+    repo: &git2::Repository,
+    update_ref: &str,
+    message: &str,
+    parents: &[&git2::Commit<'_>],
+) -> Result<git2::Oid> {
+    let mut index = repo.index().context("error opening index")?;
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .context("error adding files to index")?;
+    index.write().context("error writing index")?;
+    let tree_id = index.write_tree().context("error writing tree")?;
+    let tree = repo.find_tree(tree_id).context("error finding tree")?;
+    let signature =
+        git2::Signature::now("git-branch-grep bench", "bench@localhost")
+            .context("error creating signature")?;
+    repo.commit(
+        Some(update_ref),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        parents,
+    )
+    .context("error committing")
+}
+
+/// Commits the files to master, then checks out a branch which changes some
+/// of them, both adding and removing lines.
+fn generate(path: &Path, options: &Options) -> Result<()> {
+    let repo = git2::Repository::init(path)
+        .with_context(|| format!("error creating {}", path.display()))?;
+    let workdir = repo.workdir().context("repo has no workdir")?;
+
+    let files = (0..options.files)
+        .map(|file| {
+            (0..options.lines)
+                .map(|idx| line(file, idx, 0))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    for (file, lines) in files.iter().enumerate() {
+        write_file(workdir, file, lines)?;
+    }
+    let base_id = commit(&repo, "refs/heads/master", "base", &[])?;
+    let base = repo.find_commit(base_id).context("error finding commit")?;
+
+    repo.branch("bench", &base, true)
+        .context("error creating branch")?;
+    repo.set_head("refs/heads/bench")
+        .context("error checking out branch")?;
+    // spread the changed files through the repository
+    let stride = options.files / options.changed_files.max(1);
+    for file in (0..options.files)
+        .step_by(stride.max(1))
+        .take(options.changed_files)
+    {
+        let mut lines = files[file].clone();
+        let at = lines.len() / 2;
+        // replace about a tenth of the lines around the middle
+        let removed = (lines.len() / 10).min(lines.len() - at);
+        lines.splice(
+            at..at + removed,
+            (0..options.changed_lines).map(|idx| line(file, idx, 1)),
+        );
+        write_file(workdir, file, &lines)?;
+    }
+    commit(&repo, "HEAD", "branch", &[&base])?;
+    Ok(())
+}
+
+/// The phase timings of a single run, followed by its total time.
+fn run_once(path: &Path, args: &[&str]) -> Result<Vec<Duration>> {
+    let exe = std::env::current_exe().context("error finding executable")?;
+    let start = Instant::now();
+    let output = Command::new(exe)
+        .args(args)
+        .args(["--timings", "--color", "never", PATTERN])
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("error running git-branch-grep")?;
+    let total = start.elapsed();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!(
+            "git-branch-grep {} failed: {}",
+            args.join(" "),
+            stderr.trim()
+        );
+    }
+    let mut timings = PHASES
+        .iter()
+        .map(|phase| {
+            let prefix = format!("{}: ", phase);
+            stderr
+                .lines()
+                .find_map(|line| line.strip_prefix(prefix.as_str()))
+                .and_then(|secs| secs.strip_suffix('s')?.parse::<f64>().ok())
+                .map(Duration::from_secs_f64)
+                .with_context(|| format!("missing timing for {}", phase))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    timings.push(total);
+    Ok(timings)
+}
+
+fn measure(path: &Path, options: &Options) -> Result<()> {
+    let mut configurations: Vec<(&str, &[&str])> = vec![
+        ("regex", &[]),
+        ("ignore case", &["-i"]),
+        ("fixed strings", &["-F"]),
+        ("word", &["-w"]),
+        ("multiline", &["--multiline"]),
+    ];
+    if cfg!(feature = "pcre2") {
+        configurations.push(("pcre2", &["--pcre2"]));
+        configurations.push(("pcre2 ignore case", &["--pcre2", "-i"]));
+    }
+
+    println!(
+        "{} files of {} lines, branch changes {} files by {} lines, median of \
+         {} runs",
+        options.files,
+        options.lines,
+        options.changed_files,
+        options.changed_lines,
+        options.runs
+    );
+    let headers = PHASES.iter().copied().chain(["total"]).collect::<Vec<_>>();
+    let name_width = configurations
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    print!("{:name_width$}", "", name_width = name_width);
+    for header in &headers {
+        print!("  {:>width$}", header, width = header.len().max(9));
+    }
+    println!();
+    for (name, args) in &configurations {
+        let mut runs = (0..options.runs)
+            .map(|_| run_once(path, args))
+            .collect::<Result<Vec<_>>>()?;
+        print!("{:name_width$}", name, name_width = name_width);
+        for (idx, header) in headers.iter().enumerate() {
+            runs.sort_by_key(|timings| timings[idx]);
+            let median = runs[runs.len() / 2][idx];
+            print!(
+                "  {:>width$}",
+                format!("{:.1}ms", median.as_secs_f64() * 1000.0),
+                width = header.len().max(9)
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+/// Gets the `working-tree-encoding` attribute of a path, if it's set to
+/// anything other than UTF-8.
+pub fn working_tree_encoding(
+    repo: &git2::Repository,
+    path: &Path,
+) -> Option<String> {
+    let encoding = repo
+        .get_attr(
+            path,
+            "working-tree-encoding",
+            git2::AttrCheckFlags::FILE_THEN_INDEX,
+        )
+        .ok()
+        .flatten()?;
+    if encoding.eq_ignore_ascii_case("utf-8")
+        || encoding.eq_ignore_ascii_case("utf8")
+    {
+        return None;
+    }
+    Some(encoding.to_owned())
+}
+
+/// Decodes working tree content from the given encoding into UTF-8.
+///
+/// Only the UTF-16 family of encodings is supported, returning `None` for
+/// anything else. Like git, plain "UTF-16" uses a byte order mark if present
+/// and is otherwise big-endian.
+pub fn decode(content: &[u8], encoding: &str) -> Option<String> {
+    let encoding = encoding.to_ascii_uppercase().replace('_', "-");
+    let (content, little_endian) = match encoding.as_str() {
+        "UTF-16LE" | "UTF16LE" => (content, true),
+        "UTF-16BE" | "UTF16BE" => (content, false),
+        "UTF-16" | "UTF16" => match content {
+            [0xff, 0xfe, rest @ ..] => (rest, true),
+            [0xfe, 0xff, rest @ ..] => (rest, false),
+            _ => (content, false),
+        },
+        _ => return None,
+    };
+    let units = content
+        .chunks(2)
+        .map(|chunk| match *chunk {
+            [a, b] if little_endian => u16::from_le_bytes([a, b]),
+            [a, b] => u16::from_be_bytes([a, b]),
+            // a trailing odd byte can't be decoded
+            _ => 0xfffd,
+        })
+        .collect::<Vec<_>>();
+    Some(String::from_utf16_lossy(&units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_utf16_with_explicit_byte_order() {
+        assert_eq!(decode(b"h\0i\0", "UTF-16LE").unwrap(), "hi");
+        assert_eq!(decode(b"\0h\0i", "UTF-16BE").unwrap(), "hi");
+        assert_eq!(decode(b"h\0i\0", "utf16le").unwrap(), "hi");
+        assert_eq!(decode(b"h\0i\0", "utf_16le").unwrap(), "hi");
+    }
+
+    #[test]
+    fn decodes_utf16_by_byte_order_mark() {
+        assert_eq!(decode(b"\xff\xfeh\0i\0", "UTF-16").unwrap(), "hi");
+        assert_eq!(decode(b"\xfe\xff\0h\0i", "UTF-16").unwrap(), "hi");
+        // without a byte order mark, big-endian is assumed
+        assert_eq!(decode(b"\0h\0i", "UTF-16").unwrap(), "hi");
+    }
+
+    #[test]
+    fn decodes_crlf_and_non_ascii() {
+        assert_eq!(decode(b"a\0\r\0\n\0\xe9\0", "UTF-16LE").unwrap(), "a\r\né");
+        // a character outside the basic multilingual plane, as a surrogate
+        // pair
+        assert_eq!(decode(b"\x3d\xd8\x00\xde", "UTF-16LE").unwrap(), "😀");
+    }
+
+    #[test]
+    fn replaces_undecodable_content() {
+        assert_eq!(decode(b"", "UTF-16LE").unwrap(), "");
+        assert_eq!(decode(b"h\0i", "UTF-16LE").unwrap(), "h\u{fffd}");
+        assert_eq!(decode(b"\x3d\xd8", "UTF-16LE").unwrap(), "\u{fffd}");
+    }
+
+    #[test]
+    fn leaves_other_encodings_undecoded() {
+        assert_eq!(decode(b"hi", "ISO-8859-1"), None);
+        assert_eq!(decode(b"hi", "UTF-32"), None);
+        assert_eq!(decode(b"hi", ""), None);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::Line;
+use anyhow::{bail, Context, Result};
+use std::{path::Path, process::Command};
+
+/// Runs a command once for each line, substituting `{path}` and `{line}` in
+/// its arguments.
+pub fn run_per_line(
+    command: &str,
+    workdir: &Path,
+    lines: &[Line],
+) -> Result<()> {
+    for line in lines {
+        let path = line.path.to_string_lossy();
+        let lineno = line.lineno.to_string();
+        run(
+            command,
+            workdir,
+            &[("{path}", &*path), ("{line}", lineno.as_str())],
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs a command once for each file with matching lines, substituting
+/// `{path}` in its arguments.
+pub fn run_per_file(
+    command: &str,
+    workdir: &Path,
+    lines: &[Line],
+) -> Result<()> {
+    let mut paths = lines.iter().map(|line| &line.path).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        run(command, workdir, &[("{path}", &*path.to_string_lossy())])?;
+    }
+    Ok(())
+}
+
+/// Runs a command from the working directory, with its arguments split on
+/// whitespace rather than by a shell, so substituted paths can't be
+/// misinterpreted.
+fn run(
+    command: &str,
+    workdir: &Path,
+    substitutions: &[(&str, &str)],
+) -> Result<()> {
+    let args = command
+        .split_whitespace()
+        .map(|arg| {
+            substitutions
+                .iter()
+                .fold(arg.to_owned(), |arg, (placeholder, value)| {
+                    arg.replace(placeholder, value)
+                })
+        })
+        .collect::<Vec<_>>();
+    let (program, args) = args.split_first().context("empty command")?;
+    debug!("running {} {:?}", program, args);
+    let status = Command::new(program)
+        .args(args)
+        .current_dir(workdir)
+        .status()
+        .with_context(|| format!("error running {}", program))?;
+    if !status.success() {
+        bail!("{} exited with {}", program, status);
+    }
+    Ok(())
+}
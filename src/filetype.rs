@@ -0,0 +1,109 @@
+use anyhow::{bail, Context, Error, Result};
+use std::{collections::BTreeMap, str};
+
+/// The built-in file types and the globs their files match.
+const DEFAULT_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["*.c", "*.h"]),
+    (
+        "cpp",
+        &["*.cpp", "*.cc", "*.cxx", "*.hpp", "*.hh", "*.hxx", "*.h"],
+    ),
+    ("csharp", &["*.cs"]),
+    ("css", &["*.css", "*.scss", "*.sass", "*.less"]),
+    ("go", &["*.go"]),
+    ("html", &["*.html", "*.htm"]),
+    ("java", &["*.java"]),
+    ("js", &["*.js", "*.jsx", "*.mjs", "*.cjs"]),
+    ("json", &["*.json"]),
+    ("kotlin", &["*.kt", "*.kts"]),
+    ("markdown", &["*.md", "*.markdown"]),
+    ("php", &["*.php"]),
+    ("py", &["*.py", "*.pyi"]),
+    ("ruby", &["*.rb", "Gemfile", "Rakefile"]),
+    ("rust", &["*.rs"]),
+    ("sh", &["*.sh", "*.bash", "*.zsh"]),
+    ("sql", &["*.sql"]),
+    ("swift", &["*.swift"]),
+    ("toml", &["*.toml"]),
+    ("ts", &["*.ts", "*.tsx", "*.mts", "*.cts"]),
+    ("yaml", &["*.yaml", "*.yml"]),
+];
+
+/// A glob to add to a file type, given as `NAME:GLOB`.
+#[derive(Debug)]
+pub struct TypeAdd {
+    name: String,
+    glob: String,
+}
+
+impl str::FromStr for TypeAdd {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, glob) = s
+            .split_once(':')
+            .context("file type must be given as NAME:GLOB")?;
+        if name.is_empty() || glob.is_empty() {
+            bail!("file type must be given as NAME:GLOB");
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            glob: glob.to_owned(),
+        })
+    }
+}
+
+/// The known file types, by name.
+#[derive(Debug)]
+pub struct FileTypes(BTreeMap<String, Vec<String>>);
+
+impl FileTypes {
+    /// Builds the built-in file types along with any custom additions, which
+    /// can define new types or add globs to existing ones.
+    pub fn new(type_adds: &[TypeAdd]) -> Self {
+        let mut types = DEFAULT_TYPES
+            .iter()
+            .map(|(name, globs)| {
+                (
+                    (*name).to_owned(),
+                    globs.iter().map(|&glob| glob.to_owned()).collect(),
+                )
+            })
+            .collect::<BTreeMap<_, Vec<_>>>();
+        for TypeAdd { name, glob } in type_adds {
+            types.entry(name.clone()).or_default().push(glob.clone());
+        }
+        Self(types)
+    }
+
+    /// Gets the globs for a file type.
+    pub fn globs(&self, name: &str) -> Result<&[String]> {
+        self.0
+            .get(name)
+            .map(Vec::as_slice)
+            .with_context(|| format!("{:?} is not a known file type", name))
+    }
+
+    /// Compiles the globs of each file type, for detecting the types of
+    /// paths.
+    pub fn pathspecs(&self) -> Result<Vec<(&str, git2::Pathspec)>> {
+        self.0
+            .iter()
+            .map(|(name, globs)| {
+                let pathspec =
+                    git2::Pathspec::new(globs.iter().map(String::as_str))
+                        .with_context(|| {
+                            format!("error parsing globs for {:?}", name)
+                        })?;
+                Ok((name.as_str(), pathspec))
+            })
+            .collect()
+    }
+
+    /// Prints each file type and its globs, like `rust: *.rs`.
+    pub fn print_list(&self) {
+        for (name, globs) in &self.0 {
+            println!("{}: {}", name, globs.join(", "));
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{display_path, Line, MultiSet, COLOR};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+/// A finding from a saved run, identified by its fingerprint.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    fingerprint: String,
+    path: PathBuf,
+    content: String,
+}
+
+impl Finding {
+    fn new(line: &Line, rules: &[String]) -> Self {
+        Self {
+            fingerprint: line.fingerprint(rules),
+            path: line.path.clone(),
+            content: line.content.clone(),
+        }
+    }
+}
+
+impl PartialEq for Finding {
+    fn eq(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint
+    }
+}
+
+impl Eq for Finding {}
+
+impl Hash for Finding {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fingerprint.hash(state);
+    }
+}
+
+/// Gets the path that runs for the current branch are saved to.
+pub fn run_path(repo: &git2::Repository) -> Result<PathBuf> {
+    let head = repo.head().context("error resolving HEAD")?;
+    let branch = if head.is_branch() {
+        head.shorthand().context("branch name is not valid utf8")?
+    } else {
+        "HEAD"
+    };
+    Ok(repo.path().join("branch-grep").join("runs").join(branch))
+}
+
+/// Loads the findings of a saved run, if there is one.
+pub fn load(path: &Path) -> Result<Option<Vec<Finding>>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(None)
+        },
+        Err(error) => return Err(error).context("error reading saved run"),
+    };
+    let findings = contents
+        .lines()
+        .map(|record| {
+            let mut fields = record.split('\t').map(unescape);
+            let (path, content, fingerprint) =
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(path), Some(content), Some(fingerprint)) => {
+                        (PathBuf::from(path), content, fingerprint)
+                    },
+                    _ => bail!("malformed saved run record"),
+                };
+            Ok(Finding {
+                fingerprint,
+                path,
+                content,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(findings))
+}
+
+/// Saves the findings of this run, replacing any previously saved run.
+pub fn save(path: &Path, lines: &[Line], rules: &[String]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context("error creating saved run directory")?;
+    }
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(&escape(&line.path.to_string_lossy()));
+        contents.push('\t');
+        contents.push_str(&escape(&line.content));
+        contents.push('\t');
+        contents.push_str(&line.fingerprint(rules));
+        contents.push('\n');
+    }
+    fs::write(path, contents).context("error writing saved run")
+}
+
+/// Prints the lines of this run split into findings that are new or
+/// unchanged since the previous run, followed by the findings of the
+/// previous run which have since been fixed.
+pub fn print_comparison(
+    previous: Vec<Finding>,
+    lines: &[Line],
+    rules: &[String],
+) {
+    let mut remaining = MultiSet::new();
+    for finding in previous {
+        remaining.insert(finding);
+    }
+    let mut new = Vec::new();
+    let mut unchanged = Vec::new();
+    for line in lines {
+        if remaining.remove(&Finding::new(line, rules)) {
+            unchanged.push(line);
+        } else {
+            new.push(line);
+        }
+    }
+    let fixed = remaining.into_items();
+
+    let color = COLOR.load(Ordering::SeqCst);
+    let heading = |name: &str, style: &str, count: usize| {
+        if color {
+            println!("\x1b[{}m{}\x1b[m ({})", style, name, count);
+        } else {
+            println!("{} ({})", name, count);
+        }
+    };
+    heading("new", "32;1", new.len());
+    for line in new {
+        println!("  {}", line);
+    }
+    heading("unchanged", "1", unchanged.len());
+    for line in unchanged {
+        println!("  {}", line);
+    }
+    heading("fixed", "31;1", fixed.len());
+    for Finding { path, content, .. } in fixed {
+        if color {
+            println!("  \x1b[32m{}\x1b[m: {}", display_path(&path), content);
+        } else {
+            println!("  {}: {}", display_path(&path), content);
+        }
+    }
+}
+
+/// Escapes a field of a run record, including carriage returns, which
+/// reading the records line by line would otherwise drop from the end of a
+/// record.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_separators() {
+        assert_eq!(escape(""), "");
+        assert_eq!(escape("plain text"), "plain text");
+        assert_eq!(escape("a\tb\nc"), r"a\tb\nc");
+        assert_eq!(escape("line\r\n"), r"line\r\n");
+        assert_eq!(escape(r"C:\tmp"), r"C:\\tmp");
+    }
+
+    #[test]
+    fn unescapes_escaped_fields() {
+        assert_eq!(unescape(""), "");
+        assert_eq!(unescape(r"a\tb\nc\r"), "a\tb\nc\r");
+        assert_eq!(unescape(r"C:\\tmp"), r"C:\tmp");
+        // unknown escapes and a trailing backslash are kept as they are
+        assert_eq!(unescape(r"\q"), "q");
+        assert_eq!(unescape(r"end\"), r"end\");
+    }
+
+    #[test]
+    fn round_trips_fields() {
+        for field in
+            ["", "a\tb", "x\\ny", "\\", "\\\\t", "crlf\r\n", "\r", "é→\t"]
+        {
+            let escaped = escape(field);
+            assert!(!escaped.contains(&['\t', '\n', '\r'][..]));
+            assert_eq!(unescape(&escaped), field);
+        }
+    }
+}
@@ -2,19 +2,53 @@
 #![deny(clippy::correctness)]
 #![allow(clippy::let_and_return)]
 
-use anyhow::{bail, Context, Error, Result};
+macro_rules! debug {
+    ($msg:literal $($args:tt)*) => {
+        if $crate::DEBUG.load(::std::sync::atomic::Ordering::SeqCst) {
+            if $crate::COLOR.load(::std::sync::atomic::Ordering::SeqCst) {
+                eprintln!(concat!("\x1b[90m[DEBUG]\x1b[m ", $msg) $($args)*);
+            } else {
+                eprintln!(concat!("[DEBUG] ", $msg) $($args)*);
+            }
+        }
+    };
+}
+
+mod base;
+mod bench;
+mod encoding;
+mod exec;
+mod filetype;
+mod history;
+mod manifest;
+mod output;
+mod pattern;
+mod scan;
+mod sparse;
+mod sqlite;
+mod syntax;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
 use argh::FromArgs;
-use regex::Regex;
+use base::{BaseStrategy, Fallbacks};
+use filetype::{FileTypes, TypeAdd};
+use manifest::Manifest;
+use output::{BadgeColors, ColumnWidth, Columns, Format, GroupBy};
+use pattern::{Matcher, Patterns};
+use regex::RegexBuilder;
+use scan::{BinaryMode, DiffFilter};
 use std::{
     borrow::Borrow,
-    collections::{hash_map, HashMap},
+    cmp::Reverse,
+    collections::{hash_map, HashMap, HashSet},
     fmt,
+    fs,
     hash::Hash,
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str,
-    sync::atomic::{AtomicBool, Ordering},
-    time::Instant,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
 
 /// Search the content of diffs between git tags.
@@ -24,24 +58,435 @@ use std::{
 /// regular expression, so regex syntax must be escaped.
 #[derive(Debug, FromArgs)]
 struct Args {
-    /// the text to search with
+    /// the text to search with, optional with --list-changed, followed by
+    /// paths to limit the search to, relative to the current directory, or
+    /// only paths when patterns are given with -e or -f
     #[argh(positional)]
-    search: Regex,
-    /// the name of the parent branch to diff against, defaults to
-    /// "master"/"main"
+    search: Vec<String>,
+    /// a pattern to search with instead of the positional one, may be given
+    /// multiple times to search for any of several patterns
+    #[argh(option, short = 'e')]
+    regexp: Vec<String>,
+    /// a file to read patterns to search with from, one per line, may be
+    /// given multiple times
+    #[argh(option, short = 'f')]
+    file: Vec<PathBuf>,
+    /// the name of the parent branch to diff against, defaults to the
+    /// branch HEAD's branch tracks, if not its own remote copy, or else the
+    /// default branch: "master", "main", init.defaultBranch, or origin's HEAD
     #[argh(option, short = 'p')]
     parent: Option<String>,
-    /// a reference to a commit to diff against
+    /// a reference to a commit to diff against (repeatable)
     #[argh(option, short = 'd')]
-    diff_base: Option<String>,
+    diff_base: Vec<String>,
     /// turn on debug output
     #[argh(switch)]
     debug: bool,
+    /// print how long each phase of the run took to stderr, without the rest
+    /// of the debug output
+    #[argh(switch)]
+    timings: bool,
     /// color output, "always", "auto" (default), or "never"
     #[argh(option, default = "ColorOption::Auto")]
     color: ColorOption,
+    /// output format, "text" (default), "table", "badge", "report" (which
+    /// exits with status 1 when it fails, unless --warn-only is given),
+    /// "json", "markdown", "vimgrep", "html", "sarif", "csv", "tsv",
+    /// "teamcity", or "azure"
+    #[argh(option, default = "Format::Text")]
+    format: Format,
+    /// comma-separated columns to show in table output, any of "status",
+    /// "path", "line", "rule", "excerpt", and "fingerprint" (defaults to
+    /// "path,line,rule,excerpt")
+    #[argh(option, default = "Columns::default()")]
+    columns: Columns,
+    /// maximum width of a table column, given as COLUMN=WIDTH (repeatable)
+    #[argh(option)]
+    max_width: Vec<ColumnWidth>,
+    /// sort results by "path", "match" (matched text), or "modified" (most
+    /// recently modified files first), defaults to diff order
+    #[argh(option)]
+    sort: Option<SortKey>,
+    /// order files by "churn" (most added and removed lines first), keeping
+    /// the order of results within each file
+    #[argh(option)]
+    prioritize: Option<Priority>,
+    /// group text output by "match", listing each distinct matched string
+    /// once with the lines it was found on, or by "rule", listing each
+    /// search pattern with the lines it matched
+    #[argh(option)]
+    group_by: Option<GroupBy>,
+    /// print a frequency table of the values captured by the given group
+    /// name or index instead of the matching lines, as text or JSON
+    #[argh(option)]
+    histogram: Option<CaptureGroup>,
+    /// append the results of this run to a sqlite database at the given path
+    #[argh(option)]
+    export_sqlite: Option<PathBuf>,
+    /// write the results to a file in vim's errorformat, for loading into the
+    /// quickfix list
+    #[argh(option)]
+    quickfix: Option<PathBuf>,
+    /// write the results to a file as a Markdown task list
+    #[argh(option)]
+    checklist: Option<PathBuf>,
+    /// write per-rule match counts and the run duration to a file for the
+    /// Prometheus textfile collector
+    #[argh(option)]
+    metrics_textfile: Option<PathBuf>,
+    /// run a command once for each result from the root of the working
+    /// directory, replacing "{{path}}" and "{{line}}" in its arguments, which
+    /// are split on whitespace
+    #[argh(option)]
+    exec: Option<String>,
+    /// run a command once for each file with results from the root of the
+    /// working directory, replacing "{{path}}" in its arguments, which are
+    /// split on whitespace
+    #[argh(option)]
+    exec_per_file: Option<String>,
+    /// save the results of this run for the current branch, to be compared
+    /// against later with --compare-last
+    #[argh(switch)]
+    save_run: bool,
+    /// report which results are new, unchanged, or fixed since the last
+    /// saved run for the current branch
+    #[argh(switch)]
+    compare_last: bool,
+    /// a reference whose tree is searched for existing matches, which are
+    /// then left out of the results
+    #[argh(option)]
+    baseline_ref: Option<String>,
+    /// separator to use between path components in output, "/" or "\",
+    /// defaults to the platform separator
+    #[argh(option)]
+    path_separator: Option<PathSeparator>,
+    /// label to use for badge output, defaults to "matches"
+    #[argh(option, default = "String::from(\"matches\")")]
+    badge_label: String,
+    /// badge colors by number of matches, given as a comma-separated list of
+    /// MIN_COUNT=COLOR, defaults to "0=brightgreen,1=yellow,10=red"
+    #[argh(option, default = "BadgeColors::default()")]
+    badge_colors: BadgeColors,
+    /// only report added lines which are duplicated elsewhere in the same
+    /// file, use with an empty search pattern to check every added line
+    #[argh(switch)]
+    find_duplicates: bool,
+    /// minimum length of a line to be reported by --find-duplicates,
+    /// defaults to 20
+    #[argh(option, default = "20")]
+    min_duplicate_len: usize,
+    /// maximum number of matches allowed under a path prefix, given as
+    /// PREFIX=COUNT, where a prefix of "**" covers every path (repeatable),
+    /// added to and overriding those in a .branchgrepbudgets file at the root
+    /// of the working directory, which has one budget per line
+    #[argh(option)]
+    budget: Vec<Budget>,
+    /// search the entire content of every file touched by the diff, marking
+    /// matches on lines which were not added with "-" separators
+    #[argh(switch)]
+    full_files: bool,
+    /// list every file changed by the diff with its status and the number
+    /// of added and removed lines, after any matching lines
+    #[argh(switch)]
+    list_changed: bool,
+    /// don't leave out files outside of the sparse checkout cone
+    #[argh(switch)]
+    no_sparse_filter: bool,
+    /// only report matches in untracked files
+    #[argh(switch)]
+    untracked_only: bool,
+    /// only report matches in tracked files
+    #[argh(switch)]
+    tracked_only: bool,
+    /// only report lines added in files that are new on the branch
+    #[argh(switch)]
+    new_files_lines_only: bool,
+    /// only report lines added in files that already existed
+    #[argh(switch)]
+    edited_lines_only: bool,
+    /// maximum number of matches to show per file, the rest are summarized,
+    /// only supported with ungrouped text output
+    #[argh(option)]
+    max_per_file: Option<usize>,
+    /// size limit of the compiled search pattern, such as "10M" (the
+    /// default)
+    #[argh(option, default = "ByteSize(10 * (1 << 20))")]
+    regex_size_limit: ByteSize,
+    /// size limit of the cache used while matching the search pattern, such
+    /// as "2M" (the default)
+    #[argh(option, default = "ByteSize(2 * (1 << 20))")]
+    regex_dfa_size_limit: ByteSize,
+    /// how to pick the commit to diff against, "merge-base", "fork-point",
+    /// "upstream", "root", "exact", or "empty" to scan every line as added,
+    /// defaults to the merge base with the parent branch or the root commit
+    /// when on the root branch
+    #[argh(option)]
+    base_strategy: Option<BaseStrategy>,
+    /// how to combine the results of multiple diff bases, "union" (the
+    /// default) to report lines added relative to any base, or
+    /// "intersection" to report lines added relative to every base
+    #[argh(option, default = "BaseCombine::Union")]
+    base_combine: BaseCombine,
+    /// leave out added lines which were also added on the parent branch since
+    /// the diff base, such as those brought in by merging the parent branch
+    #[argh(switch)]
+    exclude_mainline: bool,
+    /// check that the search pattern compiles and exit without reading the
+    /// repository
+    #[argh(switch)]
+    check_patterns: bool,
+    /// only report matches within a range of lines of a file, given as
+    /// FILE:START-END, may be given multiple times
+    #[argh(option, short = 'L')]
+    line_range: Vec<LineRange>,
+    /// merge hunks separated by at most this many unchanged lines, defaults
+    /// to 0
+    #[argh(option, default = "0")]
+    interhunk: u32,
+    /// separate the path, line number, and content of each result with NUL
+    /// bytes and end each result with a NUL byte, for use with xargs -0
+    #[argh(switch, short = '0')]
+    null: bool,
+    /// print each file's path once as a heading above its lines instead of
+    /// on every line
+    #[argh(switch)]
+    heading: bool,
+    /// scan a remote-tracking branch given as REMOTE/BRANCH instead of the
+    /// working tree, diffed against its merge base with the parent branch and
+    /// fetched first if needed
+    #[argh(option)]
+    remote_branch: Option<String>,
+    /// print summary statistics after the results, including the work done
+    /// and peak memory use
+    #[argh(switch)]
+    stats: bool,
+    /// print only summary statistics instead of the results
+    #[argh(switch)]
+    stats_only: bool,
+    /// print the number of matches in each file instead of the matches
+    #[argh(switch, short = 'c')]
+    count: bool,
+    /// print matches as a Markdown list of release notes grouped by file,
+    /// using the search pattern's first capture group as the note if it has
+    /// one
+    #[argh(switch)]
+    notes_template: bool,
+    /// print only the paths of files with matches
+    #[argh(switch, short = 'l')]
+    files_with_matches: bool,
+    /// print only the paths of files with added lines but no matches
+    #[argh(switch)]
+    files_without_match: bool,
+    /// match the search pattern case-insensitively
+    #[argh(switch, short = 'i')]
+    ignore_case: bool,
+    /// also match this many unchanged lines around each change, which are
+    /// shown like existing lines
+    #[argh(option, default = "0")]
+    search_context_lines: u32,
+    /// match case-insensitively unless the search pattern contains an
+    /// uppercase character
+    #[argh(switch, short = 'S')]
+    smart_case: bool,
+    /// report exceeded budgets, a dirty working tree with --require-clean,
+    /// and a failing --format report as warnings instead of failing
+    #[argh(switch)]
+    warn_only: bool,
+    /// skip files that can't be read or decoded instead of stopping, listing
+    /// them at the end and exiting with status 2
+    #[argh(switch)]
+    keep_going: bool,
+    /// treat the search pattern as a literal string instead of a regex
+    #[argh(switch, short = 'F')]
+    fixed_strings: bool,
+    /// only match the search pattern at word boundaries
+    #[argh(switch, short = 'w')]
+    word_regexp: bool,
+    /// report added lines which don't match the search pattern instead
+    #[argh(switch, short = 'v')]
+    invert_match: bool,
+    /// match the search pattern against all the added lines of each hunk at
+    /// once, so that matches can span lines, reporting the line each match
+    /// starts on
+    #[argh(switch)]
+    multiline: bool,
+    /// compile the search pattern with PCRE2 instead, which supports
+    /// look-around and backreferences (requires the pcre2 feature)
+    #[argh(switch)]
+    pcre2: bool,
+    /// with --pcre2, how many backtracking steps a pattern may take on one
+    /// line before it's treated as not matching with a warning, defaults to
+    /// 1000000
+    #[argh(option, default = "1_000_000")]
+    pcre2_match_limit: u32,
+    /// only search changed files whose paths match a glob, such as
+    /// 'src/**/*.rs', or leave out files matching a glob starting with '!',
+    /// may be given multiple times, overrides any Grep-Paths lines in the
+    /// branch's commit messages
+    #[argh(option, short = 'g')]
+    glob: Vec<String>,
+    /// comma-separated bases to try in order when HEAD has no merge base with
+    /// its parent or no root commit, such as on orphan branches, any of
+    /// "upstream" for the
+    /// upstream branch's tip, "remote-default" for the tip of origin's
+    /// default branch, and "empty" for the empty tree, defaults to
+    /// "upstream,remote-default", or "none" to fail instead
+    #[argh(option, default = "Fallbacks::default()")]
+    fallback_base: Fallbacks,
+    /// leave out changed files whose paths match a glob, such as
+    /// 'vendor/**', may be given multiple times
+    #[argh(option)]
+    exclude: Vec<String>,
+    /// match --glob, --exclude, and file type globs regardless of case,
+    /// leaving the search pattern's case handling as it is
+    #[argh(switch)]
+    glob_case_insensitive: bool,
+    /// only search changed files of a type, such as "rust" or "js", may be
+    /// given multiple times (see --type-list)
+    #[argh(option, short = 't', long = "type")]
+    file_type: Vec<String>,
+    /// add a glob to a file type, given as NAME:GLOB, defining the type if
+    /// it isn't built in, may be given multiple times
+    #[argh(option)]
+    type_add: Vec<TypeAdd>,
+    /// print the known file types and their globs and exit
+    #[argh(switch)]
+    type_list: bool,
+    /// refuse to run when the working tree has uncommitted changes, which
+    /// would otherwise be scanned along with the branch's commits
+    #[argh(switch)]
+    require_clean: bool,
+    /// scan uncommitted changes in the working tree along with the branch's
+    /// commits (the default)
+    #[argh(switch)]
+    include_dirty: bool,
+    /// what to do with binary files, "skip" (the default), "text" to search
+    /// them as text, or "report" to only print which of them match
+    #[argh(option, default = "BinaryMode::Skip")]
+    binary: BinaryMode,
+    /// search binary files as text, like --binary text
+    #[argh(switch, short = 'a')]
+    text: bool,
+    /// write the effective configuration of this run, including the
+    /// resolved diff bases, to a TOML file for reproducing it later
+    #[argh(option)]
+    manifest: Option<PathBuf>,
+    /// rerun with the arguments and diff bases recorded in a manifest written
+    /// with --manifest, ignoring any other arguments
+    #[argh(option)]
+    from_manifest: Option<PathBuf>,
+    /// skip changed files larger than this size, such as "1M"
+    #[argh(option)]
+    max_filesize: Option<ByteSize>,
+    /// only scan files with some statuses, given as letters like git diff's
+    /// --diff-filter, "A" (added), "C" (copied), "D" (deleted), "M"
+    /// (modified), "R" (renamed), "T" (type changed), or "U" (unmerged), or
+    /// lowercase to leave those statuses out instead
+    #[argh(option)]
+    diff_filter: Option<DiffFilter>,
+    /// detect renamed files, so that moving a file doesn't count all of its
+    /// lines as added
+    #[argh(switch)]
+    detect_renames: bool,
+    /// detect copied files as well as renamed ones
+    #[argh(switch)]
+    detect_copies: bool,
+    /// how similar, as a percentage, files must be to count as renamed or
+    /// copied, defaults to 50
+    #[argh(option, default = "50")]
+    similarity_threshold: u16,
+    /// a reference to diff from, such as a tag, instead of resolving a diff
+    /// base
+    #[argh(option)]
+    from: Option<String>,
+    /// a reference to diff to, such as a branch, instead of the working
+    /// tree, diffed against its merge base with the parent branch unless
+    /// --from is given
+    #[argh(option)]
+    to: Option<String>,
+    /// show this many lines of the diff around each result in Markdown
+    /// output
+    #[argh(option, default = "0")]
+    markdown_context: u32,
+    /// only scan changes staged in the index, leaving out unstaged edits in
+    /// the working tree, like git diff --cached
+    #[argh(switch)]
+    staged: bool,
+    /// the same as --staged
+    #[argh(switch)]
+    cached: bool,
+    /// only scan unstaged edits in the working tree, diffing it against the
+    /// index rather than a diff base
+    #[argh(switch)]
+    working: bool,
+    /// the same as --working
+    #[argh(switch)]
+    unstaged: bool,
+    /// only scan what the branch's commits change, diffing against HEAD
+    /// instead of the working tree and index
+    #[argh(switch)]
+    committed: bool,
+    /// diff against the merge base of HEAD and the parent branch, like git
+    /// diff's three-dot form, the same as --base-strategy merge-base (the
+    /// default, except on the root branch)
+    #[argh(switch)]
+    merge_base: bool,
+    /// diff directly against the tip of the parent branch, like git diff's
+    /// two-dot form, the same as --base-strategy exact
+    #[argh(switch)]
+    no_merge_base: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Bench(BenchCommand),
+    Langs(LangsCommand),
+    Schema(SchemaCommand),
+}
+
+/// Time the phases of a run over a synthetic repository generated in a
+/// temporary directory, comparing the regex backends and search options.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "bench")]
+struct BenchCommand {
+    /// number of files in the synthetic repository, defaults to 1000
+    #[argh(option, default = "1000")]
+    files: usize,
+    /// number of lines in each file, defaults to 200
+    #[argh(option, default = "200")]
+    lines: usize,
+    /// number of files the branch changes, defaults to 100
+    #[argh(option, default = "100")]
+    changed_files: usize,
+    /// number of lines the branch adds to each file it changes, defaults to
+    /// 50
+    #[argh(option, default = "50")]
+    changed_lines: usize,
+    /// number of times to run each configuration, whose median is shown,
+    /// defaults to 5
+    #[argh(option, default = "5")]
+    runs: usize,
+    /// keep the synthetic repository and print its path instead of removing
+    /// it
+    #[argh(switch)]
+    keep: bool,
 }
 
+/// Count the lines added and removed by the branch in each language, using
+/// the same file types as -t.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "langs")]
+struct LangsCommand {}
+
+/// Print the JSON Schema document describing --format json output, whose
+/// schema_version field changes whenever a field is removed or changed.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "schema")]
+struct SchemaCommand {}
+
 #[derive(Debug)]
 enum ColorOption {
     Always,
@@ -62,40 +507,455 @@ impl str::FromStr for ColorOption {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+    Path,
+    Match,
+    Modified,
+}
+
+impl str::FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Self::Path),
+            "match" => Ok(Self::Match),
+            "modified" => Ok(Self::Modified),
+            s => bail!("{:?} is not a valid sort key", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Priority {
+    Churn,
+}
+
+impl str::FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "churn" => Ok(Self::Churn),
+            s => bail!("{:?} is not a valid priority", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+impl str::FromStr for CaptureGroup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(idx) => Self::Index(idx),
+            Err(_) => Self::Name(s.to_owned()),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum PathSeparator {
+    Slash,
+    Backslash,
+}
+
+impl str::FromStr for PathSeparator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "/" => Ok(Self::Slash),
+            "\\" => Ok(Self::Backslash),
+            s => bail!("{:?} is not a valid path separator", s),
+        }
+    }
+}
+
+/// A limit on the number of matches under a path prefix.
+#[derive(Debug)]
+struct Budget {
+    prefix: String,
+    max_count: usize,
+}
+
+impl str::FromStr for Budget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, max_count) = s
+            .rsplit_once('=')
+            .context("budget must be given as PREFIX=COUNT")?;
+        let prefix = prefix.trim_end_matches("**").to_owned();
+        let max_count = max_count.parse().context("invalid budget count")?;
+        Ok(Self { prefix, max_count })
+    }
+}
+
+impl Budget {
+    fn name(&self) -> String {
+        if self.prefix.is_empty() || self.prefix.ends_with('/') {
+            format!("{}**", self.prefix)
+        } else {
+            self.prefix.clone()
+        }
+    }
+
+    /// Checks whether a path is under the budget's prefix, matching whole
+    /// path components so that "src/legacy" doesn't cover "src/legacy_new".
+    fn covers(&self, path: &Path) -> bool {
+        path.starts_with(&self.prefix)
+    }
+}
+
+/// A range of lines in a file, inclusive of both ends.
+#[derive(Debug)]
+struct LineRange {
+    path: PathBuf,
+    start: u32,
+    end: u32,
+}
+
+impl str::FromStr for LineRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, range) = s
+            .rsplit_once(':')
+            .context("line range must be given as FILE:START-END")?;
+        let (start, end) = range
+            .split_once('-')
+            .context("line range must be given as FILE:START-END")?;
+        let start = start.parse().context("invalid line range start")?;
+        let end = end.parse().context("invalid line range end")?;
+        if start > end {
+            bail!("line range start must not be after its end");
+        }
+        Ok(Self {
+            path: PathBuf::from(path),
+            start,
+            end,
+        })
+    }
+}
+
+impl LineRange {
+    fn contains(&self, line: &Line) -> bool {
+        line.path == self.path && (self.start..=self.end).contains(&line.lineno)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BaseCombine {
+    Union,
+    Intersection,
+}
+
+impl str::FromStr for BaseCombine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "union" => Ok(Self::Union),
+            "intersection" => Ok(Self::Intersection),
+            s => bail!("{:?} is not a valid base combination", s),
+        }
+    }
+}
+
+/// A number of bytes, optionally given with a "K", "M", or "G" suffix.
+#[derive(Debug, Clone, Copy)]
+struct ByteSize(u64);
+
+impl str::FromStr for ByteSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, scale) = match s.char_indices().last() {
+            Some((idx, 'k' | 'K')) => (&s[..idx], 1 << 10),
+            Some((idx, 'm' | 'M')) => (&s[..idx], 1 << 20),
+            Some((idx, 'g' | 'G')) => (&s[..idx], 1 << 30),
+            _ => (s, 1),
+        };
+        let number = number
+            .parse::<u64>()
+            .with_context(|| format!("{:?} is not a valid size", s))?;
+        let size = number
+            .checked_mul(scale)
+            .with_context(|| format!("{:?} is too large", s))?;
+        Ok(Self(size))
+    }
+}
+
 static COLOR: AtomicBool = AtomicBool::new(false);
+static DEBUG: AtomicBool = AtomicBool::new(false);
+static BACKSLASH_PATHS: AtomicBool = AtomicBool::new(false);
+static INTERHUNK_LINES: AtomicU32 = AtomicU32::new(0);
+static CONTEXT_LINES: AtomicU32 = AtomicU32::new(0);
+static FORCE_TEXT: AtomicBool = AtomicBool::new(false);
+
+/// Formats a repository-relative path for output.
+///
+/// Git always uses forward slashes, so these are swapped out when
+/// backslashes are requested.
+fn display_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if BACKSLASH_PATHS.load(Ordering::SeqCst) {
+        path.replace('/', "\\")
+    } else {
+        path.into_owned()
+    }
+}
+
+/// Loads the gitignore-style patterns in `.branchgrepignore` at the root of the
+/// working directory, for paths to always leave out of results.
+fn load_branchgrepignore(
+    repo: &git2::Repository,
+) -> Result<Option<ignore::gitignore::Gitignore>> {
+    let (workdir, path) =
+        match (repo.workdir(), config_file_path(repo, ".branchgrepignore")) {
+            (Some(workdir), Some(path)) => (workdir, path),
+            _ => return Ok(None),
+        };
+    let mut builder = ignore::gitignore::GitignoreBuilder::new(workdir);
+    if let Some(error) = builder.add(&path) {
+        return Err(error).context("error reading .branchgrepignore");
+    }
+    let ignore = builder.build().context("error parsing .branchgrepignore")?;
+    debug!("ignoring {} patterns from .branchgrepignore", ignore.len());
+    Ok(Some(ignore))
+}
+
+/// Reads the budgets in the `.branchgrepbudgets` file at the root of the
+/// working directory, one `PREFIX=COUNT` per line, skipping blank lines and
+/// comments starting with `#`.
+fn load_budgets(repo: &git2::Repository) -> Result<Vec<Budget>> {
+    let path = match config_file_path(repo, ".branchgrepbudgets") {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    let contents = fs::read_to_string(&path)
+        .context("error reading .branchgrepbudgets")?;
+    let budgets = contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| {
+            line.parse().with_context(|| {
+                format!("invalid budget on .branchgrepbudgets line {}", idx + 1)
+            })
+        })
+        .collect::<Result<Vec<Budget>>>()?;
+    debug!("loaded {} budgets from .branchgrepbudgets", budgets.len());
+    Ok(budgets)
+}
+
+/// The configuration files at the root of the working directory which change
+/// a run's results.
+const CONFIG_FILE_NAMES: [&str; 2] =
+    [".branchgrepignore", ".branchgrepbudgets"];
+
+/// Gets the path of a configuration file at the root of the working
+/// directory, if it exists.
+fn config_file_path(repo: &git2::Repository, name: &str) -> Option<PathBuf> {
+    let path = repo.workdir()?.join(name);
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Converts a path relative to the current directory into a pathspec
+/// relative to the root of the working directory.
+fn repo_relative_path(repo: &git2::Repository, path: &Path) -> Result<String> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return Ok(path.to_string_lossy().into_owned()),
+    };
+    let workdir = fs::canonicalize(workdir)
+        .context("error resolving working directory")?;
+    let current_dir = std::env::current_dir()
+        .and_then(fs::canonicalize)
+        .context("error resolving current directory")?;
+    // the path itself may not exist anymore, so it's normalized by hand
+    // rather than canonicalized
+    let mut full_path = PathBuf::new();
+    for component in current_dir.join(path).components() {
+        match component {
+            std::path::Component::CurDir => {},
+            std::path::Component::ParentDir => {
+                full_path.pop();
+            },
+            component => full_path.push(component),
+        }
+    }
+    let relative_path =
+        full_path.strip_prefix(&workdir).with_context(|| {
+            format!("{} is outside the repository", path.display())
+        })?;
+    Ok(relative_path.to_string_lossy().replace('\\', "/"))
+}
+
+/// Joins a repository-relative path onto the working directory.
+///
+/// On Windows, paths longer than `MAX_PATH` are given an extended-length
+/// prefix so that they can still be opened.
+fn workdir_path(workdir: &Path, path: &Path) -> PathBuf {
+    let full_path = workdir.join(path);
+    if !cfg!(windows) {
+        return full_path;
+    }
+    // extended-length paths are not normalized, so they must only use
+    // backslashes
+    let full_path = full_path.to_string_lossy().replace('/', "\\");
+    if full_path.len() < 260 || full_path.starts_with(r"\\?\") {
+        PathBuf::from(full_path)
+    } else if let Some(unc_path) = full_path.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", unc_path))
+    } else {
+        PathBuf::from(format!(r"\\?\{}", full_path))
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 struct Line {
     content: String,
-    range: Range<usize>,
+    /// every match of the rule's pattern in the line, in order
+    ranges: Vec<Range<usize>>,
+    /// the index of the search pattern that matched
+    rule: usize,
     lineno: u32,
     path: PathBuf,
+    kind: LineKind,
+    status: git2::Delta,
 }
 
-impl fmt::Display for Line {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// a line added by the diff
+    Added,
+    /// a line that already existed in a file touched by the diff
+    Existing,
+}
+
+impl Line {
+    /// The first match in the line.
+    fn range(&self) -> Range<usize> {
+        self.ranges[0].clone()
+    }
+
+    /// Identifies a line by where it is and what it contains.
+    fn key(&self) -> (&Path, u32, &str) {
+        (&self.path, self.lineno, &self.content)
+    }
+
+    /// Identifies a match independently of its line number.
+    fn fingerprint(&self, rules: &[String]) -> String {
+        fingerprint(&self.path, &self.content, &rules[self.rule])
+    }
+
+    fn without_path(&self) -> WithoutPath<'_> {
+        WithoutPath(self)
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        with_path: bool,
+    ) -> fmt::Result {
         let Line {
             content,
-            range,
+            ranges,
             lineno,
             path,
+            kind,
+            status,
+            ..
         } = self;
-        let path = path.display();
+        let path = display_path(path);
+        let untracked = *status == git2::Delta::Untracked;
+        // like grep's context lines, existing lines use a different separator
+        let sep = match kind {
+            LineKind::Added => ':',
+            LineKind::Existing => '-',
+        };
         if COLOR.load(Ordering::SeqCst) {
-            let before = &content[..range.start];
-            let r#match = &content[range.clone()];
-            let after = &content[range.end..];
-            write!(
-                f,
-                "\x1b[32m{}\x1b[m:\x1b[33m{}\x1b[m: {}\x1b[36;1m{}\x1b[m{}",
-                path, lineno, before, r#match, after
-            )
+            let style = match kind {
+                LineKind::Added => "36;1",
+                LineKind::Existing => "36",
+            };
+            if with_path {
+                // untracked files are shown in red, like git status does
+                if untracked {
+                    write!(f, "\x1b[31m{}\x1b[m{}", path, sep)?;
+                } else {
+                    write!(f, "\x1b[32m{}\x1b[m{}", path, sep)?;
+                }
+            }
+            write!(f, "\x1b[33m{}\x1b[m{} ", lineno, sep)?;
+            if untracked {
+                write!(f, "\x1b[31m[U]\x1b[m ")?;
+            }
+            let mut end = 0;
+            for range in ranges {
+                write!(
+                    f,
+                    "{}\x1b[{}m{}\x1b[m",
+                    &content[end..range.start],
+                    style,
+                    &content[range.clone()]
+                )?;
+                end = range.end;
+            }
+            write!(f, "{}", &content[end..])
         } else {
-            write!(f, "{}:{}: {}", path, lineno, content)
+            if with_path {
+                write!(f, "{}{}", path, sep)?;
+            }
+            write!(f, "{}{} ", lineno, sep)?;
+            if untracked {
+                write!(f, "[U] ")?;
+            }
+            write!(f, "{}", content)
         }
     }
 }
 
+/// Computes a short, stable identity for a match from its path, its content
+/// with whitespace normalized, and the rule that found it.
+///
+/// Line numbers are left out since they drift as the branch changes.
+fn fingerprint(path: &Path, content: &str, rule: &str) -> String {
+    let content = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    let input = format!("{}\0{}\0{}", path.to_string_lossy(), rule, content);
+    let id = git2::Oid::hash_object(git2::ObjectType::Blob, input.as_bytes())
+        .expect("error hashing fingerprint");
+    id.to_string()[..12].to_owned()
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, true)
+    }
+}
+
+/// Displays a line without its path, for when the path is shown elsewhere.
+struct WithoutPath<'a>(&'a Line);
+
+impl fmt::Display for WithoutPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write(f, false)
+    }
+}
+
 struct MultiSet<T>(HashMap<T, usize>);
 
 impl<T> MultiSet<T>
@@ -117,6 +977,16 @@ where
         }
     }
 
+    fn into_items(self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.0
+            .into_iter()
+            .flat_map(|(k, count)| std::iter::repeat(k).take(count))
+            .collect()
+    }
+
     fn remove<Q>(&mut self, k: &Q) -> bool
     where
         T: Borrow<Q>,
@@ -136,13 +1006,142 @@ where
 }
 
 fn main() -> Result<()> {
+    let args = argh::from_env::<Args>();
+    let manifest_path = args.manifest.clone();
+    let (args, replayed_manifest) = match &args.from_manifest {
+        Some(path) => {
+            let manifest = Manifest::load(path).with_context(|| {
+                format!("error loading manifest {}", path.display())
+            })?;
+            let manifest_args =
+                manifest.args.iter().map(String::as_str).collect::<Vec<_>>();
+            let args =
+                Args::from_args(&[env!("CARGO_PKG_NAME")], &manifest_args)
+                    .map_err(|early_exit| {
+                        anyhow!(
+                            "invalid arguments in manifest: {}",
+                            early_exit.output.trim()
+                        )
+                    })?;
+            (args, Some(manifest))
+        },
+        None => (args, None),
+    };
     let Args {
         search,
+        regexp: mut patterns,
+        file: pattern_files,
         parent: parent_branch_name,
-        diff_base: base_commit_ref,
+        diff_base: base_commit_refs,
         debug,
+        timings,
         color,
-    } = argh::from_env::<Args>();
+        format,
+        columns,
+        max_width,
+        sort,
+        prioritize,
+        group_by,
+        histogram,
+        export_sqlite,
+        quickfix,
+        checklist,
+        metrics_textfile,
+        exec,
+        exec_per_file,
+        save_run,
+        compare_last,
+        baseline_ref,
+        path_separator,
+        badge_label,
+        badge_colors,
+        find_duplicates,
+        min_duplicate_len,
+        budget: budgets,
+        full_files,
+        list_changed,
+        no_sparse_filter,
+        untracked_only,
+        tracked_only,
+        new_files_lines_only,
+        edited_lines_only,
+        max_per_file,
+        regex_size_limit,
+        regex_dfa_size_limit,
+        base_strategy,
+        base_combine,
+        exclude_mainline,
+        check_patterns,
+        line_range: line_ranges,
+        interhunk,
+        null,
+        heading,
+        remote_branch,
+        stats,
+        stats_only,
+        count,
+        notes_template,
+        files_with_matches,
+        files_without_match,
+        ignore_case,
+        search_context_lines,
+        smart_case,
+        warn_only,
+        keep_going,
+        fixed_strings,
+        word_regexp,
+        invert_match,
+        multiline,
+        pcre2,
+        pcre2_match_limit,
+        glob: mut globs,
+        fallback_base,
+        exclude: mut exclude_globs,
+        glob_case_insensitive,
+        file_type: file_type_names,
+        type_add,
+        type_list,
+        require_clean,
+        include_dirty,
+        binary,
+        text,
+        manifest: _,
+        from_manifest: _,
+        max_filesize,
+        diff_filter,
+        detect_renames,
+        detect_copies,
+        similarity_threshold,
+        from: from_ref,
+        to: to_ref,
+        markdown_context,
+        staged,
+        cached,
+        working,
+        unstaged,
+        committed,
+        merge_base: use_merge_base,
+        no_merge_base,
+        command,
+    } = args;
+    let staged = staged || cached;
+    let working = working || unstaged;
+    if let Some(Command::Schema(_)) = command {
+        output::print_schema();
+        return Ok(());
+    }
+    if let Some(Command::Bench(bench)) = &command {
+        return bench::run(&bench::Options {
+            files: bench.files,
+            lines: bench.lines,
+            changed_files: bench.changed_files,
+            changed_lines: bench.changed_lines,
+            runs: bench.runs,
+            keep: bench.keep,
+        });
+    }
+    let langs = matches!(command, Some(Command::Langs(_)));
+    let start_time = Instant::now();
 
     COLOR.store(
         match color {
@@ -152,230 +1151,1728 @@ fn main() -> Result<()> {
         },
         Ordering::SeqCst,
     );
-
-    macro_rules! debug {
-        ($msg:literal $($args:tt)*) => {
-            if debug {
-                if COLOR.load(Ordering::SeqCst) {
-                    eprintln!(concat!("\x1b[90m[DEBUG]\x1b[m ", $msg) $($args)*);
-                } else {
-                    eprintln!(concat!("[DEBUG] ", $msg) $($args)*);
-                }
-            }
-        };
+    BACKSLASH_PATHS.store(
+        match path_separator {
+            Some(PathSeparator::Slash) => false,
+            Some(PathSeparator::Backslash) => true,
+            None => cfg!(windows),
+        },
+        Ordering::SeqCst,
+    );
+    DEBUG.store(debug, Ordering::SeqCst);
+    INTERHUNK_LINES.store(interhunk, Ordering::SeqCst);
+    // the diff needs enough context lines for both matching and showing
+    CONTEXT_LINES
+        .store(search_context_lines.max(markdown_context), Ordering::SeqCst);
+    if text && binary == BinaryMode::Report {
+        bail!("cannot specify both text and binary report options");
     }
+    let binary = if text { BinaryMode::Text } else { binary };
+    // libgit2 only gives the lines of binary files when forced to
+    FORCE_TEXT.store(binary != BinaryMode::Skip, Ordering::SeqCst);
 
-    if parent_branch_name.is_some() && base_commit_ref.is_some() {
+    if parent_branch_name.is_some() && !base_commit_refs.is_empty() {
         bail!("cannot specify both parent branch and direct diff base options");
     }
-
-    let repo = git2::Repository::open_from_env()
-        .context("error opening repository")?;
-
-    let commit_resolution_timer = Instant::now();
-    let base_commit = if let Some(base_commit_ref) = base_commit_ref {
-        debug!("using direct base reference");
-        let base_commit = repo
-            .resolve_reference_from_short_name(&base_commit_ref)
-            .and_then(|reference| reference.peel_to_commit())
-            .context("error resolving base commit")?;
-        base_commit
+    if use_merge_base && !base_commit_refs.is_empty() {
+        bail!("cannot specify both merge-base and direct diff base options");
+    }
+    if group_by.is_some() && !matches!(format, Format::Text) {
+        bail!("grouping is only supported with text output");
+    }
+    if compare_last && !matches!(format, Format::Text) {
+        bail!("comparing runs is only supported with text output");
+    }
+    if heading && (!matches!(format, Format::Text) || group_by.is_some()) {
+        bail!("headings are only supported with ungrouped text output");
+    }
+    if heading && null {
+        bail!("cannot specify both heading and null options");
+    }
+    if count && (!matches!(format, Format::Text) || group_by.is_some()) {
+        bail!("counts are only supported with ungrouped text output");
+    }
+    if count && (heading || null) {
+        bail!("cannot specify count with heading or null options");
+    }
+    if notes_template
+        && (!matches!(format, Format::Text)
+            || group_by.is_some()
+            || count
+            || heading
+            || null)
+    {
+        bail!("release notes are only supported with plain text output");
+    }
+    if (files_with_matches || files_without_match)
+        && (!matches!(format, Format::Text)
+            || group_by.is_some()
+            || count
+            || heading
+            || null
+            || notes_template)
+    {
+        bail!("listing files is only supported with plain text output");
+    }
+    if files_with_matches && files_without_match {
+        bail!("cannot specify both files-with-matches and files-without-match");
+    }
+    if null && (!matches!(format, Format::Text) || group_by.is_some()) {
+        bail!(
+            "NUL-separated output is only supported with ungrouped text output"
+        );
+    }
+    if histogram.is_some() && !matches!(format, Format::Text | Format::Json) {
+        bail!("histograms are only supported with text or JSON output");
+    }
+    // only plain text output says how many matches were left out, and the
+    // other formats would silently drop them
+    if max_per_file.is_some()
+        && !count
+        && !matches!(format, Format::Badge)
+        && (!matches!(format, Format::Text)
+            || group_by.is_some()
+            || notes_template
+            || null)
+    {
+        bail!("max-per-file is only supported with ungrouped text output");
+    }
+    if remote_branch.is_some()
+        && (!base_commit_refs.is_empty() || base_strategy.is_some())
+    {
+        bail!(
+            "cannot specify a diff base or base strategy with a remote branch"
+        );
+    }
+    if from_ref.is_some()
+        && (!base_commit_refs.is_empty()
+            || base_strategy.is_some()
+            || remote_branch.is_some())
+    {
+        bail!(
+            "cannot specify a diff base, base strategy, or remote branch with \
+             --from"
+        );
+    }
+    if to_ref.is_some() && remote_branch.is_some() {
+        bail!("cannot specify both a remote branch and --to");
+    }
+    if use_merge_base && no_merge_base {
+        bail!("cannot specify both merge-base and no-merge-base options");
+    }
+    if (use_merge_base || no_merge_base)
+        && (base_strategy.is_some() || from_ref.is_some())
+    {
+        bail!(
+            "cannot specify a base strategy or --from with merge-base or \
+             no-merge-base options"
+        );
+    }
+    let base_strategy = if no_merge_base {
+        Some(BaseStrategy::Exact)
+    } else if use_merge_base {
+        // even on the root branch, where the root commit would be used
+        Some(BaseStrategy::MergeBase)
     } else {
-        let head_commit = repo
-            .head()
-            .and_then(|reference| reference.peel_to_commit())
-            .context("error resolving head commit")?;
-        debug!("HEAD commit: {}", head_commit.id());
-
-        let root_branch_head_commit = ["refs/heads/master", "refs/heads/main"]
+        base_strategy
+    };
+    if stats
+        && !stats_only
+        && !matches!(format, Format::Text | Format::Table | Format::Report)
+    {
+        bail!("statistics can only follow text, table, or report output");
+    }
+    if multiline && invert_match {
+        bail!("cannot specify both multiline and invert-match options");
+    }
+    if require_clean && include_dirty {
+        bail!("cannot specify both require-clean and include-dirty options");
+    }
+    if staged && (to_ref.is_some() || remote_branch.is_some()) {
+        bail!("cannot scan staged changes of another commit");
+    }
+    if working && (to_ref.is_some() || remote_branch.is_some()) {
+        bail!("cannot scan unstaged changes of another commit");
+    }
+    if working && staged {
+        bail!("cannot specify both staged and working options");
+    }
+    if committed
+        && (staged || working || to_ref.is_some() || remote_branch.is_some())
+    {
+        bail!(
+            "cannot specify staged, working, a remote branch, or --to with \
+             --committed"
+        );
+    }
+    // unstaged edits are diffed against the index, so the diff base only
+    // scopes them by the branch's Grep-Paths lines
+    if working && (base_commit_refs.len() > 1 || exclude_mainline) {
+        bail!(
+            "cannot specify multiple diff bases or exclude-mainline with \
+             --working"
+        );
+    }
+    if similarity_threshold > 100 {
+        bail!("similarity threshold must be a percentage");
+    }
+    let find_similar = |diff: &mut git2::Diff<'_>| {
+        if !detect_renames && !detect_copies {
+            return Ok(());
+        }
+        diff.find_similar(Some(
+            git2::DiffFindOptions::new()
+                .renames(true)
+                .copies(detect_copies)
+                .for_untracked(true)
+                .rename_threshold(similarity_threshold)
+                .copy_threshold(similarity_threshold),
+        ))
+    };
+    if markdown_context > 0 && !matches!(format, Format::Markdown) {
+        bail!("Markdown context is only supported with Markdown output");
+    }
+    if untracked_only && tracked_only {
+        bail!("cannot specify both untracked-only and tracked-only options");
+    }
+    if new_files_lines_only && edited_lines_only {
+        bail!(
+            "cannot specify both new-files-lines-only and edited-lines-only \
+             options"
+        );
+    }
+    for pattern_file in &pattern_files {
+        let contents = fs::read_to_string(pattern_file).with_context(|| {
+            format!("error reading pattern file {}", pattern_file.display())
+        })?;
+        patterns.extend(
+            contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    // like grep, with patterns given as options every positional argument is
+    // a path
+    let mut search = search.into_iter();
+    if patterns.is_empty() && pattern_files.is_empty() {
+        patterns.extend(search.next());
+    }
+    let paths = search.map(PathBuf::from).collect::<Vec<_>>();
+    let file_types = FileTypes::new(&type_add);
+    if type_list {
+        file_types.print_list();
+        return Ok(());
+    }
+    let type_globs = file_type_names
+        .iter()
+        .map(|name| file_types.globs(name))
+        .collect::<Result<Vec<_>>>()?
+        .concat();
+    if patterns.is_empty() && (check_patterns || !(list_changed || langs)) {
+        bail!("no search pattern given");
+    }
+    let search = if patterns.is_empty() {
+        None
+    } else {
+        let matchers = patterns
             .into_iter()
-            .find_map(|name| {
-                repo.find_reference(name)
-                    .and_then(|reference| reference.peel_to_commit())
-                    .ok()
+            .map(|pattern| {
+                let pattern = if fixed_strings {
+                    regex::escape(&pattern)
+                } else {
+                    pattern
+                };
+                let pattern = if word_regexp {
+                    format!(r"\b(?:{})\b", pattern)
+                } else {
+                    pattern
+                };
+                let case_insensitive = ignore_case
+                    || (smart_case && !has_uppercase_literal(&pattern));
+                let matcher: Result<Box<dyn Matcher>> = if pcre2 {
+                    pattern::build_pcre2(
+                        &pattern,
+                        case_insensitive,
+                        multiline,
+                        pcre2_match_limit,
+                    )
+                } else {
+                    RegexBuilder::new(&pattern)
+                        .case_insensitive(case_insensitive)
+                        .multi_line(multiline)
+                        .dot_matches_new_line(multiline)
+                        .size_limit(regex_size_limit.0 as usize)
+                        .dfa_size_limit(regex_dfa_size_limit.0 as usize)
+                        .build()
+                        .map(|regex| Box::new(regex) as _)
+                        .map_err(Error::new)
+                };
+                match matcher {
+                    // one oversized rule shouldn't stop the others from
+                    // running, though checking patterns should still fail
+                    Err(error)
+                        if !check_patterns && exceeds_size_limit(&error) =>
+                    {
+                        eprintln!(
+                            "warning: skipping search pattern {:?}, which \
+                             exceeds the regex size limit",
+                            pattern
+                        );
+                        Ok(None)
+                    },
+                    matcher => matcher.map(Some).with_context(|| {
+                        format!("error compiling search pattern {:?}", pattern)
+                    }),
+                }
             })
-            .context("root branch not found")?;
-        let parent_commit = if let Some(parent_branch_name) = parent_branch_name
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        if matchers.is_empty() {
+            bail!("every search pattern exceeds the regex size limit");
+        }
+        Some(Patterns::new(matchers))
+    };
+    if let (Some(search), Some(group)) = (&search, &histogram) {
+        if !search
+            .matchers()
+            .iter()
+            .any(|matcher| matcher.has_group(group))
         {
-            repo.find_reference(&format!("refs/heads/{}", parent_branch_name))
-                .and_then(|reference| reference.peel_to_commit())
-                .context("error resolving parent commit")?
+            match group {
+                CaptureGroup::Index(idx) => {
+                    bail!("search pattern has no capture group {}", idx)
+                },
+                CaptureGroup::Name(name) => {
+                    bail!(
+                        "search pattern has no capture group named {:?}",
+                        name
+                    )
+                },
+            }
+        }
+    }
+    if check_patterns {
+        if let Some(search) = &search {
+            for rule in search.rules() {
+                println!("{}: ok", rule);
+            }
+        }
+        return Ok(());
+    }
+
+    let repo = git2::Repository::open_from_env()
+        .context("error opening repository")?;
+
+    let commit_resolution_timer = Instant::now();
+    // the commit to scan instead of the working tree, if any
+    let target_commit = if let Some(remote_branch) = &remote_branch {
+        Some(base::remote_branch_commit(&repo, remote_branch)?)
+    } else if let Some(to_ref) = &to_ref {
+        Some(
+            repo.revparse_single(to_ref)
+                .and_then(|object| object.peel_to_commit())
+                .context("error resolving --to commit")?,
+        )
+    } else {
+        None
+    };
+    // how the bases were picked is recorded with the results
+    let (base_strategy_name, base_commits) =
+        if let Some(manifest) = &replayed_manifest {
+            let base_commits = manifest
+                .base_commits
+                .iter()
+                .map(|&id| {
+                    if id == base::empty_tree_id() {
+                        return Ok(None);
+                    }
+                    repo.find_commit(id)
+                        .map(Some)
+                        .context("error finding manifest base commit")
+                })
+                .collect::<Result<Vec<_>>>()?;
+            ("manifest".to_owned(), base_commits)
+        } else if let Some(from_ref) = &from_ref {
+            let base_commit = repo
+                .revparse_single(from_ref)
+                .and_then(|object| object.peel_to_commit())
+                .context("error resolving --from commit")?;
+            (BaseStrategy::Exact.to_string(), vec![Some(base_commit)])
+        } else if let Some(target_commit) = &target_commit {
+            let parent_commit =
+                base::parent_commit(&repo, parent_branch_name.as_deref())?;
+            if no_merge_base {
+                (BaseStrategy::Exact.to_string(), vec![Some(parent_commit)])
+            } else {
+                let base_commit =
+                    base::merge_base(&repo, target_commit, &parent_commit)?;
+                (BaseStrategy::MergeBase.to_string(), vec![Some(base_commit)])
+            }
+        } else if base_commit_refs.is_empty() {
+            let (strategy, base_commit) = base::resolve_or_fall_back(
+                &repo,
+                base_strategy,
+                parent_branch_name.as_deref(),
+                &fallback_base,
+            )?;
+            (strategy, vec![base_commit])
         } else {
-            root_branch_head_commit.clone()
+            let base_commits = base_commit_refs
+                .iter()
+                .map(|base_commit_ref| {
+                    base::resolve(
+                        &repo,
+                        base_strategy,
+                        parent_branch_name.as_deref(),
+                        Some(base_commit_ref),
+                    )
+                    .map(|(_, base_commit)| base_commit)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // direct bases are always used as-is
+            (BaseStrategy::Exact.to_string(), base_commits)
         };
-        debug!("parent commit: {}", parent_commit.id());
-
-        if head_commit.id() == parent_commit.id() {
-            if head_commit.id() == root_branch_head_commit.id() {
-                // if HEAD is on the root branch, use the root commit of the
-                // repo
-                debug!(
-                    "HEAD is on root branch, using root commit as diff base"
+    debug!("base strategy: {}", base_strategy_name);
+    let commit_resolution_timer = commit_resolution_timer.elapsed();
+    let head_id = match &target_commit {
+        Some(target_commit) => Some(target_commit.id()),
+        None => repo.head().ok().and_then(|head| head.target()),
+    };
+
+    if let Some(manifest) = &replayed_manifest {
+        match manifest.head_commit {
+            Some(manifest_head_id) if Some(manifest_head_id) != head_id => {
+                eprintln!(
+                    "warning: manifest was written at {}, but HEAD is at {}",
+                    manifest_head_id,
+                    head_id.map_or("nothing".to_owned(), |id| id.to_string())
+                );
+            },
+            _ => {},
+        }
+        for (path, id) in &manifest.files {
+            if manifest::file_id(path).ok() != Some(*id) {
+                eprintln!(
+                    "warning: {} has changed since the manifest was written",
+                    path.display()
+                );
+            }
+        }
+        for path in CONFIG_FILE_NAMES
+            .iter()
+            .filter_map(|name| config_file_path(&repo, name))
+        {
+            if !manifest.files.iter().any(|(recorded, _)| *recorded == path) {
+                eprintln!(
+                    "warning: {} was added since the manifest was written",
+                    path.display()
+                );
+            }
+        }
+    }
+    // the empty tree stands in for a missing base wherever bases are recorded
+    let base_ids = base_commits
+        .iter()
+        .map(|base_commit| {
+            base_commit
+                .as_ref()
+                .map_or_else(base::empty_tree_id, |base_commit| {
+                    base_commit.id()
+                })
+        })
+        .collect::<Vec<_>>();
+    let run_args = match &replayed_manifest {
+        Some(manifest) => manifest.args.clone(),
+        None => manifest::recorded_args(),
+    };
+    if let Some(manifest_path) = &manifest_path {
+        let files = pattern_files
+            .iter()
+            .cloned()
+            .chain(
+                CONFIG_FILE_NAMES
+                    .iter()
+                    .filter_map(|name| config_file_path(&repo, name)),
+            )
+            .map(|path| {
+                let id = manifest::file_id(&path)?;
+                Ok((path, id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Manifest {
+            args: run_args.clone(),
+            base_commits: base_ids.clone(),
+            head_commit: head_id,
+            files,
+        }
+        .write(manifest_path)
+        .context("error writing manifest")?;
+    }
+
+    // branches can declare which paths they're about with Grep-Paths lines in
+    // their commit messages
+    if globs.is_empty() {
+        if let Some(head_id) = head_id {
+            globs = base::scope_globs(&repo, head_id, &base_commits)
+                .context("error reading Grep-Paths directives")?;
+            if !globs.is_empty() {
+                debug!("scoped by commit messages to {:?}", globs);
+            }
+        }
+    }
+
+    let target_tree = if committed {
+        Some(
+            repo.head()
+                .and_then(|head| head.peel_to_tree())
+                .context("error getting HEAD tree")?,
+        )
+    } else {
+        target_commit
+            .as_ref()
+            .map(|target_commit| target_commit.tree())
+            .transpose()
+            .context("error getting target tree")?
+    };
+
+    // JSON and SARIF metadata say whether uncommitted changes were scanned
+    let dirty_count = if target_tree.is_none()
+        && (require_clean || matches!(format, Format::Json | Format::Sarif))
+    {
+        Some(
+            dirty_path_count(&repo)
+                .context("error checking working tree status")?,
+        )
+    } else {
+        None
+    };
+    match dirty_count {
+        Some(dirty_count) if require_clean && dirty_count > 0 => {
+            if warn_only {
+                eprintln!(
+                    "warning: working tree has {} uncommitted changes",
+                    dirty_count
                 );
-                let root_commit = repo
-                    .revwalk()
-                    .and_then(|mut revwalk| {
-                        revwalk.push_head()?;
-                        revwalk
-                            .find_map(|id| {
-                                (|| {
-                                    let id = id?;
-                                    let commit = repo.find_commit(id)?;
-                                    if commit.parent_count() == 0 {
-                                        return Ok(Some(commit));
-                                    }
-                                    Ok(None)
-                                })()
-                                .transpose()
-                            })
-                            .transpose()
-                    })
-                    .context("error finding root commit")?
-                    .context("root commit not found")?;
-                root_commit
             } else {
-                bail!("HEAD and parent refs are the same")
+                bail!("working tree has {} uncommitted changes", dirty_count);
             }
-        } else {
-            // otherwise, find the merge base between HEAD and master
-            debug!("using merge base between HEAD and parent as diff base");
-            let merge_base_commit = repo
-                .merge_base(head_commit.id(), parent_commit.id())
-                .and_then(|id| repo.find_commit(id))
-                .context("error getting merge base commit")?;
-            merge_base_commit
+        },
+        _ => {},
+    }
+
+    // the working tree has nothing to do with a target commit, so sparse
+    // checkouts and skip-worktree entries don't apply to it
+    let sparse_cone = if no_sparse_filter || target_tree.is_some() {
+        None
+    } else {
+        sparse::SparseCone::load(&repo)?
+    };
+    if let Some(sparse_cone) = &sparse_cone {
+        debug!("filtering by sparse checkout cone: {:?}", sparse_cone);
+    }
+    let unchanged_paths = if target_tree.is_some() {
+        HashSet::new()
+    } else {
+        assumed_unchanged_paths(&repo).context("error reading index")?
+    };
+    debug!(
+        "skipping {} skip-worktree or assume-unchanged paths",
+        unchanged_paths.len()
+    );
+    // negated globs can't go in the diff's pathspec, since libgit2 matches
+    // nothing when a pathspec has only negated patterns
+    let (negated_globs, globs) = globs
+        .into_iter()
+        .partition::<Vec<_>, _>(|glob| glob.starts_with('!'));
+    exclude_globs.extend(
+        negated_globs
+            .into_iter()
+            .map(|glob| glob.trim_start_matches('!').to_owned()),
+    );
+    // types narrow whatever the globs select rather than adding to it, so
+    // they're matched separately from the diff's pathspec
+    let branchgrepignore = load_branchgrepignore(&repo)?;
+    // budgets given as options override the file's budgets for the same
+    // prefix
+    let budgets = {
+        let mut file_budgets = load_budgets(&repo)?;
+        file_budgets.retain(|file_budget| {
+            !budgets
+                .iter()
+                .any(|budget| budget.prefix == file_budget.prefix)
+        });
+        file_budgets.extend(budgets);
+        file_budgets
+    };
+    let paths = paths
+        .iter()
+        .map(|path| repo_relative_path(&repo, path))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        // the root of the working directory covers everything
+        .filter(|path| !path.is_empty())
+        .collect::<Vec<_>>();
+    // paths decide what the diff covers when they're given, so globs can only
+    // narrow it further rather than adding to it; the diff's pathspec is
+    // always case-sensitive, so case-insensitive globs are matched afterwards
+    // too
+    let (pathspecs, glob_pathspec) = if globs.is_empty() {
+        (paths, None)
+    } else if paths.is_empty() && !glob_case_insensitive {
+        (globs, None)
+    } else {
+        let glob_pathspec =
+            git2::Pathspec::new(globs.iter().map(String::as_str))
+                .context("error parsing globs")?;
+        (paths, Some(glob_pathspec))
+    };
+    let type_pathspec = if type_globs.is_empty() {
+        None
+    } else {
+        Some(
+            git2::Pathspec::new(type_globs.iter().map(String::as_str))
+                .context("error parsing file type globs")?,
+        )
+    };
+    let exclude_pathspec = if exclude_globs.is_empty() {
+        None
+    } else {
+        Some(
+            git2::Pathspec::new(exclude_globs.iter().map(String::as_str))
+                .context("error parsing exclude globs")?,
+        )
+    };
+    let glob_flags = if glob_case_insensitive {
+        git2::PathspecFlags::IGNORE_CASE
+    } else {
+        git2::PathspecFlags::DEFAULT
+    };
+    // excluded files are left out of both sides of the diff, so that their
+    // removed lines don't cancel out lines added elsewhere
+    let is_excluded = |path: &Path| {
+        sparse_cone
+            .as_ref()
+            .map_or(false, |sparse_cone| !sparse_cone.contains(path))
+            || unchanged_paths.contains(path)
+            || exclude_pathspec.as_ref().map_or(false, |pathspec| {
+                pathspec.matches_path(path, glob_flags)
+            })
+            || type_pathspec.as_ref().map_or(false, |pathspec| {
+                !pathspec.matches_path(path, glob_flags)
+            })
+            || glob_pathspec.as_ref().map_or(false, |pathspec| {
+                !pathspec.matches_path(path, glob_flags)
+            })
+            || branchgrepignore.as_ref().map_or(false, |ignore| {
+                ignore.matched_path_or_any_parents(path, false).is_ignore()
+            })
+    };
+    // libgit2 skips files outside the pathspec while diffing, including
+    // untracked directories, which is cheaper than filtering them out
+    // afterwards
+    let pathspec_options = || {
+        let mut options = diff_options();
+        for pathspec in &pathspecs {
+            options.pathspec(pathspec.as_str());
         }
+        options
     };
-    let commit_resolution_timer = commit_resolution_timer.elapsed();
 
-    debug!("diff base commit: {}", base_commit.id());
-    let diff_timer = Instant::now();
-    let old_tree = base_commit.tree().context("error getting old tree")?;
-    let diff = repo
-        .diff_tree_to_workdir_with_index(
-            Some(&old_tree),
-            Some(
-                git2::DiffOptions::new()
-                    .include_untracked(true)
-                    .recurse_untracked_dirs(true)
-                    .include_unmodified(true)
-                    .ignore_filemode(true)
-                    .ignore_whitespace(true)
-                    .context_lines(0),
+    let mut diff_timer = Duration::ZERO;
+    let mut process_diff_timer = Duration::ZERO;
+    let mut changed_files = Vec::<output::ChangedFile>::new();
+    let mut combined_scan: Option<scan::Scan> = None;
+    // listing files only needs one match from each, unless something after
+    // the scan looks at the matching lines themselves
+    let first_match_per_file = (files_with_matches || files_without_match)
+        && base_commits.len() == 1
+        && !exclude_mainline
+        && !full_files
+        && line_ranges.is_empty()
+        && !find_duplicates
+        && baseline_ref.is_none()
+        && !matches!(sort, Some(SortKey::Match))
+        && histogram.is_none()
+        && budgets.is_empty()
+        && export_sqlite.is_none()
+        && quickfix.is_none()
+        && checklist.is_none()
+        && metrics_textfile.is_none()
+        && exec.is_none()
+        && exec_per_file.is_none()
+        && !save_run
+        && !compare_last
+        && !stats
+        && !stats_only;
+    for base_commit in &base_commits {
+        match base_commit {
+            Some(base_commit) => {
+                debug!("diff base commit: {}", base_commit.id())
+            },
+            None => debug!("diff base is the empty tree"),
+        }
+        let timer = Instant::now();
+        let old_tree = base_commit
+            .as_ref()
+            .map(|base_commit| base_commit.tree())
+            .transpose()
+            .context("error getting old tree")?;
+        let diff = match &target_tree {
+            Some(target_tree) => repo.diff_tree_to_tree(
+                old_tree.as_ref(),
+                Some(target_tree),
+                Some(&mut pathspec_options()),
             ),
-        )
-        // FIXME: find_similar is too aggressive
-        // .and_then(|mut diff| {
-        //     diff.find_similar(Some(git2::DiffFindOptions::new().all(true)))?;
-        //     Ok(diff)
-        // })
+            None if working => {
+                repo.diff_index_to_workdir(None, Some(&mut pathspec_options()))
+            },
+            None if staged => repo.diff_tree_to_index(
+                old_tree.as_ref(),
+                None,
+                Some(&mut pathspec_options()),
+            ),
+            None => repo.diff_tree_to_workdir_with_index(
+                old_tree.as_ref(),
+                Some(&mut pathspec_options()),
+            ),
+        }
+        .and_then(|mut diff| {
+            find_similar(&mut diff)?;
+            Ok(diff)
+        })
         .context("error diffing")?;
-    let diff_timer = diff_timer.elapsed();
-
-    let process_diff_timer = Instant::now();
-    let mut added_lines = Vec::new();
-    let mut removed_lines = MultiSet::new();
-    process_diff(&diff, git2::DiffFormat::Patch, |delta, _hunk, line| {
-        let added = match line.origin_value() {
-            git2::DiffLineType::Addition => true,
-            git2::DiffLineType::Deletion => false,
-            _ => return Ok(()),
-        };
-        let file = if added {
-            delta.new_file()
-        } else {
-            delta.old_file()
-        };
-        if file.is_binary() {
+        diff_timer += timer.elapsed();
+
+        let timer = Instant::now();
+        let base_scan = scan::scan(
+            &repo,
+            &diff,
+            &scan::ScanOptions {
+                search: search.as_ref(),
+                is_excluded: &is_excluded,
+                invert: invert_match,
+                multiline,
+                keep_going,
+                binary,
+                max_filesize: max_filesize.map(|size| size.0),
+                diff_filter: diff_filter.as_ref(),
+                match_context: search_context_lines > 0,
+                keep_hunks: markdown_context > 0,
+                first_match_per_file,
+            },
+        )?;
+        process_diff_timer += timer.elapsed();
+
+        if list_changed {
+            for delta in diff.deltas() {
+                if delta.status() == git2::Delta::Unmodified {
+                    continue;
+                }
+                let path =
+                    match delta.new_file().path().or(delta.old_file().path()) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                if is_excluded(path)
+                    || changed_files.iter().any(|file| file.path == path)
+                {
+                    continue;
+                }
+                let (added, removed) = base_scan
+                    .line_counts
+                    .get(path)
+                    .copied()
+                    .unwrap_or_default();
+                changed_files.push(output::ChangedFile {
+                    status: status_char(delta.status()),
+                    path: path.to_owned(),
+                    added,
+                    removed,
+                });
+            }
+        }
+
+        combined_scan = Some(match (combined_scan, base_combine) {
+            (None, _) => base_scan,
+            (Some(mut scan), BaseCombine::Union) => {
+                scan.union(base_scan);
+                scan
+            },
+            (Some(mut scan), BaseCombine::Intersection) => {
+                scan.intersection(base_scan);
+                scan
+            },
+        });
+    }
+    let scan::Scan {
+        mut lines,
+        touched_paths,
+        line_counts,
+        cancelled_count,
+        errors: mut file_errors,
+        binary_matches,
+        hunks,
+        usage,
+    } = combined_scan.expect("no diff bases");
+
+    if langs {
+        let pathspecs = file_types.pathspecs()?;
+        let mut counts = HashMap::<&str, (usize, usize)>::new();
+        for (path, (added, removed)) in &line_counts {
+            let language = pathspecs
+                .iter()
+                .find(|(_, pathspec)| {
+                    pathspec.matches_path(path, git2::PathspecFlags::DEFAULT)
+                })
+                .map_or("other", |(name, _)| name);
+            let count = counts.entry(language).or_default();
+            count.0 += added;
+            count.1 += removed;
+        }
+        let mut languages = counts
+            .into_iter()
+            .map(|(language, (added, removed))| {
+                (language.to_owned(), added, removed)
+            })
+            .collect::<Vec<_>>();
+        languages.sort_by(
+            |(a_language, a_added, a_removed),
+             (b_language, b_added, b_removed)| {
+                (Reverse(a_added + a_removed), a_language)
+                    .cmp(&(Reverse(b_added + b_removed), b_language))
+            },
+        );
+        output::print_languages(&languages);
+        return Ok(());
+    }
+
+    let search = match search {
+        Some(search) => search,
+        None => {
+            output::print_changed_files(&changed_files);
             return Ok(());
+        },
+    };
+
+    let line_print_timer = Instant::now();
+    // a report's verdict decides the exit status, so that it never claims a
+    // failure that scripts can't see
+    let mut report_failed = false;
+    let metadata = output::Metadata {
+        base_strategy: &base_strategy_name,
+        base_commits: &base_ids,
+        head_commit: head_id,
+        dirty: dirty_count.map(|dirty_count| dirty_count > 0),
+        args: &run_args,
+    };
+    if exclude_mainline {
+        let parent_tree =
+            base::parent_commit(&repo, parent_branch_name.as_deref())?
+                .tree()
+                .context("error getting parent tree")?;
+        let mut mainline_lines = HashSet::new();
+        for base_commit in &base_commits {
+            let old_tree = base_commit
+                .as_ref()
+                .map(|base_commit| base_commit.tree())
+                .transpose()
+                .context("error getting old tree")?;
+            let diff = repo
+                .diff_tree_to_tree(
+                    old_tree.as_ref(),
+                    Some(&parent_tree),
+                    Some(&mut pathspec_options()),
+                )
+                .and_then(|mut diff| {
+                    find_similar(&mut diff)?;
+                    Ok(diff)
+                })
+                .context("error diffing mainline")?;
+            let mainline_scan = scan::scan(
+                &repo,
+                &diff,
+                &scan::ScanOptions {
+                    search: Some(&search),
+                    is_excluded: &is_excluded,
+                    invert: invert_match,
+                    multiline,
+                    keep_going,
+                    binary,
+                    max_filesize: max_filesize.map(|size| size.0),
+                    diff_filter: diff_filter.as_ref(),
+                    match_context: search_context_lines > 0,
+                    keep_hunks: false,
+                    first_match_per_file: false,
+                },
+            )?;
+            mainline_lines.extend(
+                mainline_scan
+                    .lines
+                    .into_iter()
+                    .filter(|line| line.kind == LineKind::Added)
+                    .map(|line| (line.path, line.content)),
+            );
         }
-        let content = str::from_utf8(line.content())
-            .context("error converting line content to utf8")?;
-        let content = content.trim();
-        // if the line is either added or deleted, one of these must be Some
-        let lineno = line
-            .new_lineno()
-            .or_else(|| line.old_lineno())
-            .expect("no lineno");
-        let path = match file.path() {
-            Some(path) => path,
-            None => return Ok(()),
-        };
-        if let Some(r#match) = search.find(content) {
-            if added {
-                let line = Line {
-                    content: content.to_owned(),
-                    range: r#match.range(),
-                    lineno,
-                    path: path.to_owned(),
-                };
-                debug!("added line: {}", line);
-                added_lines.push(line);
-            } else {
-                if debug {
-                    let line = Line {
+        lines.retain(|line| {
+            let from_mainline = mainline_lines
+                .contains(&(line.path.clone(), line.content.clone()));
+            if from_mainline {
+                debug!("filtering out line added on mainline: {}", line);
+            }
+            !from_mainline
+        });
+    }
+    if full_files {
+        let added_linenos = lines
+            .iter()
+            .map(|line| (line.path.as_path(), line.lineno))
+            .collect::<HashSet<_>>();
+        let mut existing_lines = Vec::new();
+        for (path, &(id, status)) in &touched_paths {
+            let content = match read_new_file(&repo, path, id) {
+                Ok(content) => content,
+                Err(error) if keep_going => {
+                    debug!("skipping {}: {:#}", path.display(), error);
+                    file_errors.push((path.clone(), error));
+                    continue;
+                },
+                Err(error) => return Err(error),
+            };
+            for (lineno, content) in
+                (1..).zip(String::from_utf8_lossy(&content).lines())
+            {
+                if added_linenos.contains(&(path.as_path(), lineno)) {
+                    continue;
+                }
+                let content = content.trim();
+                if let Some((ranges, rule)) =
+                    find_match(&search, content, invert_match)
+                {
+                    existing_lines.push(Line {
                         content: content.to_owned(),
-                        range: r#match.range(),
+                        ranges,
+                        rule,
                         lineno,
-                        path: path.to_owned(),
-                    };
-                    debug!("removed line: {}", line);
+                        path: path.clone(),
+                        kind: LineKind::Existing,
+                        status,
+                    });
                 }
-                removed_lines.insert(content.to_owned());
             }
         }
-        Ok(())
-    })
-    .context("error processing diff")?;
-    let process_diff_timer = process_diff_timer.elapsed();
+        lines.extend(existing_lines);
+        lines.sort_by(|a, b| (&a.path, a.lineno).cmp(&(&b.path, b.lineno)));
+    }
+    if !line_ranges.is_empty() {
+        lines.retain(|line| {
+            line_ranges.iter().any(|range| range.contains(line))
+        });
+    }
+    if untracked_only || tracked_only {
+        lines.retain(|line| {
+            (line.status == git2::Delta::Untracked) == untracked_only
+        });
+    }
+    if new_files_lines_only || edited_lines_only {
+        lines.retain(|line| {
+            let new_file = matches!(
+                line.status,
+                git2::Delta::Added | git2::Delta::Untracked
+            );
+            new_file == new_files_lines_only
+        });
+    }
+    if find_duplicates {
+        let mut file_lines = HashMap::new();
+        for line in &lines {
+            if file_lines.contains_key(&line.path) {
+                continue;
+            }
+            // context lines can match in files with only deletions, which
+            // have nothing to count duplicates in
+            let (id, _) = match touched_paths.get(&line.path) {
+                Some(&touched) => touched,
+                None => continue,
+            };
+            let content = match read_new_file(&repo, &line.path, id) {
+                Ok(content) => content,
+                Err(error) if keep_going => {
+                    debug!("skipping {}: {:#}", line.path.display(), error);
+                    file_errors.push((line.path.clone(), error));
+                    // with no counts, none of the file's lines are kept
+                    file_lines.insert(line.path.clone(), HashMap::new());
+                    continue;
+                },
+                Err(error) => return Err(error),
+            };
+            let mut counts = HashMap::new();
+            for file_line in String::from_utf8_lossy(&content).lines() {
+                *counts.entry(file_line.trim().to_owned()).or_insert(0) += 1;
+            }
+            file_lines.insert(line.path.clone(), counts);
+        }
+        lines.retain(|line| {
+            line.content.chars().count() >= min_duplicate_len
+                && file_lines
+                    .get(&line.path)
+                    .and_then(|counts| counts.get(&line.content))
+                    .map_or(false, |&count| count > 1)
+        });
+    }
+    if let Some(baseline_ref) = baseline_ref {
+        let baseline_tree = repo
+            .revparse_single(&baseline_ref)
+            .and_then(|object| object.peel_to_tree())
+            .context("error resolving baseline tree")?;
+        let mut baseline_fingerprints = baseline_matches(
+            &repo,
+            &baseline_tree,
+            &search,
+            invert_match,
+            lines.iter().map(|line| line.path.as_path()),
+        )
+        .context("error searching baseline tree")?;
+        lines.retain(|line| {
+            let in_baseline =
+                baseline_fingerprints.remove(&line.fingerprint(search.rules()));
+            if in_baseline {
+                debug!("filtering out line in baseline: {}", line);
+            }
+            !in_baseline
+        });
+    }
+    match sort {
+        Some(SortKey::Path) => {
+            lines.sort_by(|a, b| (&a.path, a.lineno).cmp(&(&b.path, b.lineno)))
+        },
+        Some(SortKey::Match) => lines.sort_by(|a, b| {
+            (&a.content[a.range()], &a.path, a.lineno).cmp(&(
+                &b.content[b.range()],
+                &b.path,
+                b.lineno,
+            ))
+        }),
+        Some(SortKey::Modified) => {
+            let workdir =
+                repo.workdir().context("repository has no workdir")?;
+            let mut mtimes = HashMap::new();
+            for line in &lines {
+                mtimes.entry(line.path.clone()).or_insert_with(|| {
+                    // files that no longer exist in the working tree sort
+                    // last
+                    workdir_path(workdir, &line.path)
+                        .metadata()
+                        .and_then(|metadata| metadata.modified())
+                        .unwrap_or(SystemTime::UNIX_EPOCH)
+                });
+            }
+            lines.sort_by(|a, b| {
+                (Reverse(mtimes[&a.path]), &a.path, a.lineno).cmp(&(
+                    Reverse(mtimes[&b.path]),
+                    &b.path,
+                    b.lineno,
+                ))
+            });
+        },
+        None => {},
+    }
+    match prioritize {
+        Some(Priority::Churn) => {
+            let churn = |path: &Path| {
+                line_counts
+                    .get(path)
+                    .map_or(0, |(added, removed)| added + removed)
+            };
+            // the sort is stable, so lines keep their order within each file
+            lines.sort_by(|a, b| {
+                (Reverse(churn(&a.path)), &a.path)
+                    .cmp(&(Reverse(churn(&b.path)), &b.path))
+            });
+        },
+        None => {},
+    }
 
-    let line_print_timer = Instant::now();
-    for line in added_lines {
-        if removed_lines.remove(&line.content) {
-            debug!("filtering out added & removed line: {}", line);
+    // each line counts against the most specific budget covering its path
+    let mut budget_counts = vec![0; budgets.len()];
+    for line in &lines {
+        let budget = budgets
+            .iter()
+            .enumerate()
+            .filter(|(_, budget)| budget.covers(&line.path))
+            .max_by_key(|(_, budget)| budget.prefix.len());
+        if let Some((idx, _)) = budget {
+            budget_counts[idx] += 1;
+        }
+    }
+    let exceeded_budgets = budgets
+        .iter()
+        .zip(budget_counts)
+        .filter(|(budget, count)| *count > budget.max_count)
+        .map(|(budget, count)| {
+            format!(
+                "{}: {} matches, {} allowed",
+                budget.name(),
+                count,
+                budget.max_count
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let matched_file_count = lines
+        .iter()
+        .map(|line| &line.path)
+        .collect::<HashSet<_>>()
+        .len();
+    let run_stats = output::Stats {
+        files_changed: line_counts.len(),
+        files_with_matches: matched_file_count,
+        matches: lines.len(),
+        cancelled: cancelled_count,
+        usage,
+        elapsed: Duration::ZERO,
+    };
+
+    if let Some(export_sqlite) = export_sqlite {
+        sqlite::export(
+            &export_sqlite,
+            &sqlite::Run {
+                base_commits: &base_ids,
+                head_commit: head_id,
+                rules: search.rules(),
+            },
+            &lines,
+        )
+        .context("error exporting to sqlite")?;
+    }
+    if let Some(quickfix) = quickfix {
+        output::write_quickfix(&quickfix, &lines)
+            .context("error writing quickfix file")?;
+    }
+    if let Some(checklist) = checklist {
+        output::write_checklist(&checklist, &lines)
+            .context("error writing checklist file")?;
+    }
+    if let Some(metrics_textfile) = metrics_textfile {
+        output::write_metrics(
+            &metrics_textfile,
+            &lines,
+            search.rules(),
+            start_time.elapsed(),
+        )
+        .context("error writing metrics file")?;
+    }
+    if exec.is_some() || exec_per_file.is_some() {
+        let workdir = repo
+            .workdir()
+            .context("cannot run commands in a bare repository")?;
+        if let Some(command) = &exec {
+            exec::run_per_line(command, workdir, &lines)?;
+        }
+        if let Some(command) = &exec_per_file {
+            exec::run_per_file(command, workdir, &lines)?;
+        }
+    }
+
+    let run_path = if save_run || compare_last {
+        Some(history::run_path(&repo)?)
+    } else {
+        None
+    };
+    let previous_run = if compare_last {
+        let run_path = run_path.as_deref().expect("no run path");
+        let previous_run =
+            history::load(run_path)?.context("no saved run to compare to")?;
+        Some(previous_run)
+    } else {
+        None
+    };
+    if save_run {
+        let run_path = run_path.as_deref().expect("no run path");
+        history::save(run_path, &lines, search.rules())?;
+        debug!("saved run to {}", run_path.display());
+    }
+
+    if stats_only {
+        output::print_stats(&output::Stats {
+            elapsed: start_time.elapsed(),
+            ..run_stats
+        });
+    } else if let Some(previous_run) = previous_run {
+        history::print_comparison(previous_run, &lines, search.rules());
+    } else if let Some(group) = histogram {
+        let mut counts = HashMap::<&str, usize>::new();
+        for line in &lines {
+            let value =
+                search.matchers()[line.rule].capture(&line.content, &group);
+            if let Some(value) = value {
+                *counts.entry(value).or_default() += 1;
+            }
+        }
+        let mut counts = counts
+            .into_iter()
+            .map(|(value, count)| (value.to_owned(), count))
+            .collect::<Vec<_>>();
+        counts.sort_by(|(a_value, a_count), (b_value, b_count)| {
+            b_count.cmp(a_count).then(a_value.cmp(b_value))
+        });
+        if matches!(format, Format::Json) {
+            output::print_histogram_json(&counts);
         } else {
-            println!("{}", line);
+            output::print_histogram(&counts);
+        }
+    } else {
+        let mut omitted_counts = HashMap::new();
+        // badges and counts summarize every match, so nothing is left out of
+        // them
+        let max_per_file =
+            max_per_file.filter(|_| !matches!(format, Format::Badge) && !count);
+        if let Some(max_per_file) = max_per_file {
+            let mut counts = HashMap::<PathBuf, usize>::new();
+            lines.retain(|line| {
+                let count = counts.entry(line.path.clone()).or_default();
+                *count += 1;
+                *count <= max_per_file
+            });
+            omitted_counts = counts
+                .into_iter()
+                .filter(|(_, count)| *count > max_per_file)
+                .map(|(path, count)| (path, count - max_per_file))
+                .collect();
+        }
+        match format {
+            Format::Text => match group_by {
+                Some(GroupBy::Match) => output::print_grouped_by_match(&lines),
+                Some(GroupBy::Rule) => {
+                    output::print_grouped_by_rule(&lines, search.rules())
+                },
+                None if files_with_matches => {
+                    let mut seen_paths = HashSet::new();
+                    output::print_paths(
+                        lines
+                            .iter()
+                            .map(|line| &line.path)
+                            .filter(|path| seen_paths.insert(*path)),
+                    )
+                },
+                None if files_without_match => {
+                    let matched_paths = lines
+                        .iter()
+                        .map(|line| &line.path)
+                        .collect::<HashSet<_>>();
+                    let mut paths = touched_paths
+                        .keys()
+                        .filter(|path| !matched_paths.contains(path))
+                        .collect::<Vec<_>>();
+                    paths.sort();
+                    output::print_paths(paths)
+                },
+                None if notes_template => {
+                    output::print_notes(&lines, search.matchers())
+                },
+                None if count => output::print_counts(&lines),
+                None if null => output::print_null_separated(&lines),
+                None if heading => output::print_with_headings(
+                    &lines,
+                    search.rules(),
+                    &omitted_counts,
+                ),
+                None => {
+                    output::print_lines(&lines, search.rules(), &omitted_counts)
+                },
+            },
+            Format::Table => output::print_table(
+                &lines,
+                search.rules(),
+                &columns.0,
+                &max_width,
+            ),
+            Format::Badge => {
+                output::print_badge(&lines, &badge_label, &badge_colors)
+            },
+            Format::Report => {
+                report_failed = !output::print_report(
+                    &lines,
+                    search.rules(),
+                    &exceeded_budgets,
+                );
+            },
+            Format::Json => {
+                output::print_json(&lines, search.rules(), &metadata)
+            },
+            Format::Markdown => output::print_markdown(
+                &lines,
+                search.rules(),
+                &hunks,
+                markdown_context as usize,
+            ),
+            Format::Vimgrep => output::print_vimgrep(&lines),
+            Format::Html => output::print_html(&lines, search.rules()),
+            Format::Sarif => {
+                output::print_sarif(&lines, search.rules(), &metadata)
+            },
+            Format::Csv => output::print_csv(&lines),
+            Format::Tsv => output::print_tsv(&lines),
+            Format::Teamcity => output::print_teamcity(&lines, search.rules()),
+            Format::Azure => output::print_azure(&lines, search.rules()),
+        }
+    }
+    if list_changed {
+        output::print_changed_files(&changed_files);
+    }
+    if stats && !stats_only {
+        println!();
+        output::print_stats(&output::Stats {
+            elapsed: start_time.elapsed(),
+            ..run_stats
+        });
+    }
+    for path in &binary_matches {
+        // other formats are meant to be parsed, so these go to stderr
+        if matches!(format, Format::Text) {
+            println!("Binary file {} matches", display_path(path));
+        } else {
+            eprintln!("Binary file {} matches", display_path(path));
         }
     }
     let line_print_timer = line_print_timer.elapsed();
 
-    if debug {
+    let phase_timers = [
+        ("commit resolution", commit_resolution_timer),
+        ("diff", diff_timer),
+        ("process diff", process_diff_timer),
+        ("line print", line_print_timer),
+    ];
+    if timings {
+        // microseconds, since bench reads these back
+        for (name, timer) in &phase_timers {
+            eprintln!("{}: {:.6}s", name, timer.as_secs_f64());
+        }
+    } else if debug {
         debug!("timings:");
-        macro_rules! show_timer {
-            ($name:expr, $timer:expr) => {
-                debug!("  {}: {:.3}s", $name, $timer.as_secs_f32());
-            };
+        for (name, timer) in &phase_timers {
+            debug!("  {}: {:.3}s", name, timer.as_secs_f32());
         }
-        show_timer!("commit resolution", commit_resolution_timer);
-        show_timer!("diff", diff_timer);
-        show_timer!("process diff", process_diff_timer);
-        show_timer!("line print", line_print_timer);
+    }
+
+    for (path, error) in &file_errors {
+        eprintln!("error: skipped {}: {:#}", display_path(path), error);
+    }
+    for (rule, matcher) in search.rules().iter().zip(search.matchers()) {
+        let limit_hits = matcher.limit_hits();
+        if limit_hits > 0 {
+            eprintln!(
+                "warning: search pattern {:?} hit its match limit on {} \
+                 lines, which were treated as not matching",
+                rule, limit_hits
+            );
+        }
+    }
+
+    if !exceeded_budgets.is_empty() {
+        if warn_only {
+            eprintln!(
+                "warning: budget exceeded for {}",
+                exceeded_budgets.join("; ")
+            );
+        } else {
+            bail!("budget exceeded for {}", exceeded_budgets.join("; "));
+        }
+    }
+    if !file_errors.is_empty() {
+        std::process::exit(2);
+    }
+    if report_failed && !warn_only {
+        std::process::exit(1);
     }
 
     Ok(())
 }
 
-fn process_diff<F>(
-    diff: &git2::Diff<'_>,
-    format: git2::DiffFormat,
-    mut cb: F,
-) -> Result<()>
-where
-    F: FnMut(
-        git2::DiffDelta<'_>,
-        Option<git2::DiffHunk<'_>>,
-        git2::DiffLine<'_>,
-    ) -> Result<()>,
-{
-    let mut cb_result = Ok(());
-    let print_result = diff
-        .print(format, |delta, hunk, line| match cb(delta, hunk, line) {
-            Ok(()) => true,
-            Err(error) => {
-                cb_result = Err(error);
-                false
-            },
+/// Finds the ranges of the search patterns' matches in a line, along with the
+/// rule that matched.
+///
+/// When inverted, lines which don't match get an empty range at their start
+/// and the first rule, and lines which do match get none.
+fn find_match(
+    search: &Patterns,
+    content: &str,
+    invert: bool,
+) -> Option<(Vec<Range<usize>>, usize)> {
+    match (search.find_all(content), invert) {
+        (Some(r#match), false) => Some(r#match),
+        (None, true) => Some((vec![0..0], 0)),
+        _ => None,
+    }
+}
+
+/// Checks whether a pattern failed to compile only because it was larger than
+/// the regex size limit.
+fn exceeds_size_limit(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<regex::Error>(),
+        Some(regex::Error::CompiledTooBig(_))
+    )
+}
+
+/// Checks whether a regex pattern contains any uppercase characters outside
+/// of escape sequences such as `\S` or `\p{Greek}`.
+fn has_uppercase_literal(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escaped = chars.next();
+            // Unicode classes name their class with one letter, such as
+            // `\pL`, or in braces
+            if matches!(escaped, Some('p' | 'P')) && chars.next() == Some('{') {
+                chars.by_ref().find(|&c| c == '}');
+            }
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Options used for every diff.
+fn diff_options() -> git2::DiffOptions {
+    let mut options = git2::DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_unmodified(true)
+        .ignore_filemode(true)
+        .ignore_whitespace(true)
+        .force_text(FORCE_TEXT.load(Ordering::SeqCst))
+        .context_lines(CONTEXT_LINES.load(Ordering::SeqCst))
+        .interhunk_lines(INTERHUNK_LINES.load(Ordering::SeqCst));
+    options
+}
+
+/// Counts the paths with uncommitted changes, staged or not, including
+/// untracked files.
+fn dirty_path_count(repo: &git2::Repository) -> Result<usize> {
+    let statuses = repo.statuses(Some(
+        git2::StatusOptions::new()
+            .include_untracked(true)
+            .include_ignored(false)
+            .exclude_submodules(true),
+    ))?;
+    Ok(statuses
+        .iter()
+        .filter(|entry| entry.status() != git2::Status::CURRENT)
+        .count())
+}
+
+/// Gets the paths in the index marked as skip-worktree or assume-unchanged.
+///
+/// The working tree copies of these files can't be trusted, so they shouldn't
+/// be diffed against.
+fn assumed_unchanged_paths(
+    repo: &git2::Repository,
+) -> Result<HashSet<PathBuf>> {
+    // GIT_INDEX_ENTRY_VALID
+    const ASSUME_UNCHANGED: u16 = 1 << 15;
+    // GIT_INDEX_ENTRY_SKIP_WORKTREE
+    const SKIP_WORKTREE: u16 = 1 << 14;
+
+    let index = repo.index()?;
+    let paths = index
+        .iter()
+        .filter(|entry| {
+            entry.flags & ASSUME_UNCHANGED != 0
+                || entry.flags_extended & SKIP_WORKTREE != 0
         })
-        .context("error in iterating diff lines");
-    cb_result.and(print_result)
+        .filter_map(|entry| str::from_utf8(&entry.path).ok().map(PathBuf::from))
+        .collect();
+    Ok(paths)
+}
+
+/// Gets the single-letter status code git uses for a delta.
+fn status_char(status: git2::Delta) -> char {
+    match status {
+        git2::Delta::Unmodified => ' ',
+        git2::Delta::Added => 'A',
+        git2::Delta::Deleted => 'D',
+        git2::Delta::Modified => 'M',
+        git2::Delta::Renamed => 'R',
+        git2::Delta::Copied => 'C',
+        git2::Delta::Ignored => '!',
+        git2::Delta::Untracked => '?',
+        git2::Delta::Typechange => 'T',
+        git2::Delta::Unreadable => 'X',
+        git2::Delta::Conflicted => 'U',
+    }
+}
+
+/// Reads the content of a file on the new side of the diff.
+///
+/// The blob with the given ID is used if it is in the object database, so that
+/// the content is exactly what was diffed. Otherwise the file is read from the
+/// working tree.
+fn read_new_file(
+    repo: &git2::Repository,
+    path: &Path,
+    id: git2::Oid,
+) -> Result<Vec<u8>> {
+    if !id.is_zero() {
+        if let Ok(blob) = repo.find_blob(id) {
+            return Ok(blob.content().to_owned());
+        }
+    }
+    let workdir = repo.workdir().context("repository has no workdir")?;
+    fs::read(workdir_path(workdir, path)).context("error reading file")
+}
+
+/// Searches the given paths in a tree, returning the fingerprints of the
+/// matching lines found.
+fn baseline_matches<'a>(
+    repo: &git2::Repository,
+    tree: &git2::Tree<'_>,
+    search: &Patterns,
+    invert: bool,
+    paths: impl Iterator<Item = &'a Path>,
+) -> Result<MultiSet<String>> {
+    let mut paths = paths.collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    let mut matches = MultiSet::new();
+    for path in paths {
+        let entry = match tree.get_path(path) {
+            Ok(entry) => entry,
+            Err(error) if error.code() == git2::ErrorCode::NotFound => continue,
+            Err(error) => return Err(error.into()),
+        };
+        let blob = match entry.to_object(repo)?.into_blob() {
+            Ok(blob) => blob,
+            Err(_) => continue,
+        };
+        if blob.is_binary() {
+            continue;
+        }
+        let content = String::from_utf8_lossy(blob.content());
+        for line in content.lines() {
+            let line = line.trim();
+            if let Some((_, rule)) = find_match(search, line, invert) {
+                matches.insert(fingerprint(path, line, &search.rules()[rule]));
+            }
+        }
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_budgets() {
+        let budget = "src/legacy/**=20".parse::<Budget>().unwrap();
+        assert_eq!(budget.prefix, "src/legacy/");
+        assert_eq!(budget.max_count, 20);
+        assert_eq!(budget.name(), "src/legacy/**");
+
+        let budget = "**=0".parse::<Budget>().unwrap();
+        assert_eq!(budget.prefix, "");
+        assert_eq!(budget.max_count, 0);
+        assert_eq!(budget.name(), "**");
+
+        let budget = "src/main.rs=3".parse::<Budget>().unwrap();
+        assert_eq!(budget.prefix, "src/main.rs");
+        assert_eq!(budget.name(), "src/main.rs");
+    }
+
+    #[test]
+    fn budgets_cover_whole_path_components() {
+        let budget = "src/legacy/**=20".parse::<Budget>().unwrap();
+        assert!(budget.covers(Path::new("src/legacy/a.rs")));
+        assert!(budget.covers(Path::new("src/legacy/deep/b.rs")));
+        assert!(!budget.covers(Path::new("src/legacy_new/a.rs")));
+        assert!(!budget.covers(Path::new("src/legacy.rs")));
+        assert!(!budget.covers(Path::new("lib/src/legacy/a.rs")));
+
+        let budget = "src/legacy=20".parse::<Budget>().unwrap();
+        assert!(budget.covers(Path::new("src/legacy/a.rs")));
+        assert!(!budget.covers(Path::new("src/legacy_new/a.rs")));
+
+        let budget = "src/main.rs=1".parse::<Budget>().unwrap();
+        assert!(budget.covers(Path::new("src/main.rs")));
+        assert!(!budget.covers(Path::new("src/main.rs.bak")));
+
+        let budget = "**=0".parse::<Budget>().unwrap();
+        assert!(budget.covers(Path::new("a.rs")));
+        assert!(budget.covers(Path::new("src/legacy/a.rs")));
+    }
+
+    #[test]
+    fn splits_budgets_at_the_last_equals_sign() {
+        let budget = "a=b/**=2".parse::<Budget>().unwrap();
+        assert_eq!(budget.prefix, "a=b/");
+        assert_eq!(budget.max_count, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_budgets() {
+        assert!("".parse::<Budget>().is_err());
+        assert!("src/**".parse::<Budget>().is_err());
+        assert!("src/**=".parse::<Budget>().is_err());
+        assert!("src/**=-1".parse::<Budget>().is_err());
+        assert!("src/**=many".parse::<Budget>().is_err());
+    }
+
+    #[test]
+    fn parses_byte_sizes_with_suffixes() {
+        assert_eq!("0".parse::<ByteSize>().unwrap().0, 0);
+        assert_eq!("1024".parse::<ByteSize>().unwrap().0, 1024);
+        assert_eq!("1K".parse::<ByteSize>().unwrap().0, 1 << 10);
+        assert_eq!("1k".parse::<ByteSize>().unwrap().0, 1 << 10);
+        assert_eq!("2M".parse::<ByteSize>().unwrap().0, 2 << 20);
+        assert_eq!("3g".parse::<ByteSize>().unwrap().0, 3 << 30);
+    }
+
+    #[test]
+    fn rejects_invalid_byte_sizes() {
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("K".parse::<ByteSize>().is_err());
+        assert!("1T".parse::<ByteSize>().is_err());
+        assert!("1KB".parse::<ByteSize>().is_err());
+        assert!("1.5M".parse::<ByteSize>().is_err());
+        assert!("-1".parse::<ByteSize>().is_err());
+        assert!(" 1K".parse::<ByteSize>().is_err());
+        assert!("1é".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn rejects_byte_sizes_that_overflow() {
+        let error = "18446744073709551615K".parse::<ByteSize>().unwrap_err();
+        assert!(error.to_string().contains("too large"));
+    }
+
+    fn line(path: &str, lineno: u32) -> Line {
+        Line {
+            content: String::new(),
+            ranges: Vec::new(),
+            rule: 0,
+            lineno,
+            path: PathBuf::from(path),
+            kind: LineKind::Added,
+            status: git2::Delta::Modified,
+        }
+    }
+
+    #[test]
+    fn parses_line_ranges() {
+        let range = "src/main.rs:10-20".parse::<LineRange>().unwrap();
+        assert_eq!(range.path, Path::new("src/main.rs"));
+        assert_eq!((range.start, range.end), (10, 20));
+
+        let range = "a.rs:5-5".parse::<LineRange>().unwrap();
+        assert_eq!((range.start, range.end), (5, 5));
+    }
+
+    #[test]
+    fn splits_line_ranges_at_the_last_colon() {
+        let range = "C:/src/a:b.rs:1-2".parse::<LineRange>().unwrap();
+        assert_eq!(range.path, Path::new("C:/src/a:b.rs"));
+        assert_eq!((range.start, range.end), (1, 2));
+    }
+
+    #[test]
+    fn rejects_invalid_line_ranges() {
+        assert!("".parse::<LineRange>().is_err());
+        assert!("a.rs".parse::<LineRange>().is_err());
+        assert!("a.rs:5".parse::<LineRange>().is_err());
+        assert!("a.rs:-5".parse::<LineRange>().is_err());
+        assert!("a.rs:5-".parse::<LineRange>().is_err());
+        assert!("a.rs:1-2-3".parse::<LineRange>().is_err());
+        assert!("a.rs:x-y".parse::<LineRange>().is_err());
+        assert!("a.rs:20-10".parse::<LineRange>().is_err());
+    }
+
+    #[test]
+    fn line_ranges_contain_lines_of_their_file_inclusively() {
+        let range = "a.rs:10-20".parse::<LineRange>().unwrap();
+        assert!(range.contains(&line("a.rs", 10)));
+        assert!(range.contains(&line("a.rs", 20)));
+        assert!(!range.contains(&line("a.rs", 9)));
+        assert!(!range.contains(&line("a.rs", 21)));
+        assert!(!range.contains(&line("b.rs", 15)));
+    }
+
+    #[test]
+    fn finds_uppercase_literals() {
+        assert!(has_uppercase_literal("TODO"));
+        assert!(has_uppercase_literal("fix Me"));
+        assert!(has_uppercase_literal("[A-Z]+"));
+        assert!(has_uppercase_literal("Édition"));
+        assert!(has_uppercase_literal(r"\\A"));
+        assert!(has_uppercase_literal(r"\p{Greek}X"));
+        assert!(has_uppercase_literal(r"\pLX"));
+    }
+
+    #[test]
+    fn ignores_uppercase_in_escapes() {
+        assert!(!has_uppercase_literal(""));
+        assert!(!has_uppercase_literal("todo"));
+        assert!(!has_uppercase_literal(r"\S+\W\D"));
+        assert!(!has_uppercase_literal(r"\p{Greek}"));
+        assert!(!has_uppercase_literal(r"\P{Lu}"));
+        assert!(!has_uppercase_literal(r"\pL\PN"));
+        assert!(!has_uppercase_literal(r"\p{Greek"));
+        assert!(!has_uppercase_literal("trailing\\"));
+    }
+
+    #[test]
+    fn fingerprints_are_short_hex_ids() {
+        let id = fingerprint(Path::new("a.rs"), "// TODO", "TODO");
+        assert_eq!(id.len(), 12);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(id, fingerprint(Path::new("a.rs"), "// TODO", "TODO"));
+    }
+
+    #[test]
+    fn fingerprints_ignore_whitespace_differences() {
+        let id = fingerprint(Path::new("a.rs"), "let x = 1; // TODO", "TODO");
+        for content in [
+            "  let x = 1; // TODO",
+            "let  x =\t1; // TODO\r",
+            "let x = 1;\n// TODO",
+        ] {
+            assert_eq!(fingerprint(Path::new("a.rs"), content, "TODO"), id);
+        }
+        assert_ne!(
+            fingerprint(Path::new("a.rs"), "letx = 1; // TODO", "TODO"),
+            id
+        );
+    }
+
+    #[test]
+    fn fingerprints_depend_on_path_rule_and_content() {
+        let id = fingerprint(Path::new("a.rs"), "TODO", "TODO");
+        assert_ne!(fingerprint(Path::new("b.rs"), "TODO", "TODO"), id);
+        assert_ne!(fingerprint(Path::new("a.rs"), "TODO", "todo"), id);
+        assert_ne!(fingerprint(Path::new("a.rs"), "TODO!", "TODO"), id);
+        // fields are separated, so text can't move from one to another
+        assert_ne!(
+            fingerprint(Path::new("a"), "b", ""),
+            fingerprint(Path::new(""), "b", "a")
+        );
+        assert_ne!(fingerprint(Path::new(""), "", ""), "");
+    }
+
+    #[test]
+    fn fingerprints_lines_by_their_rule() {
+        let rules = ["TODO".to_owned(), "FIXME".to_owned()];
+        let mut matched = line("a.rs", 1);
+        matched.content = "TODO FIXME".to_owned();
+        let todo = matched.fingerprint(&rules);
+        matched.rule = 1;
+        assert_ne!(matched.fingerprint(&rules), todo);
+        // line numbers drift, so they're left out
+        matched.lineno = 100;
+        matched.rule = 0;
+        assert_eq!(matched.fingerprint(&rules), todo);
+    }
 }
@@ -0,0 +1,159 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The effective configuration of a run, for reproducing it later.
+#[derive(Debug)]
+pub struct Manifest {
+    /// the command line arguments, without the program name or --manifest
+    pub args: Vec<String>,
+    /// the resolved diff bases, with the empty tree for an empty base
+    pub base_commits: Vec<git2::Oid>,
+    pub head_commit: Option<git2::Oid>,
+    /// the blob ID of each file read for configuration: pattern files, and
+    /// the .branchgrepignore and .branchgrepbudgets files
+    pub files: Vec<(PathBuf, git2::Oid)>,
+}
+
+impl Manifest {
+    /// Writes the manifest as TOML.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        writeln!(
+            contents,
+            "# written by {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(
+            contents,
+            "version = {}",
+            toml_string(env!("CARGO_PKG_VERSION"))
+        )?;
+        writeln!(contents, "args = {}", toml_array(&self.args))?;
+        let base_commits = self
+            .base_commits
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        writeln!(contents, "base_commits = {}", toml_array(&base_commits))?;
+        if let Some(head_commit) = self.head_commit {
+            writeln!(
+                contents,
+                "head_commit = {}",
+                toml_string(&head_commit.to_string())
+            )?;
+        }
+        writeln!(contents)?;
+        writeln!(contents, "[files]")?;
+        for (path, id) in &self.files {
+            writeln!(
+                contents,
+                "{} = {}",
+                toml_string(&path.to_string_lossy()),
+                toml_string(&id.to_string())
+            )?;
+        }
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Reads a manifest written by [`Manifest::write`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let manifest = contents
+            .parse::<toml::Value>()
+            .context("error parsing manifest")?;
+        let strings = |key: &str| -> Result<Vec<&str>> {
+            manifest
+                .get(key)
+                .and_then(|value| value.as_array())
+                .with_context(|| format!("manifest has no {} array", key))?
+                .iter()
+                .map(|value| {
+                    value.as_str().with_context(|| {
+                        format!("manifest {} must be strings", key)
+                    })
+                })
+                .collect()
+        };
+        let args = strings("args")?.into_iter().map(str::to_owned).collect();
+        let base_commits = strings("base_commits")?
+            .into_iter()
+            .map(git2::Oid::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid manifest base commit")?;
+        let head_commit = manifest
+            .get("head_commit")
+            .map(|value| {
+                let id = value
+                    .as_str()
+                    .context("manifest head_commit must be a string")?;
+                git2::Oid::from_str(id).context("invalid manifest head commit")
+            })
+            .transpose()?;
+        let files = match manifest.get("files") {
+            Some(files) => files
+                .as_table()
+                .context("manifest files must be a table")?
+                .iter()
+                .map(|(path, id)| {
+                    let id = id
+                        .as_str()
+                        .context("manifest file IDs must be strings")?;
+                    let id = git2::Oid::from_str(id)
+                        .context("invalid manifest file ID")?;
+                    Ok((PathBuf::from(path), id))
+                })
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        if base_commits.is_empty() {
+            bail!("manifest has no base commits");
+        }
+        Ok(Self {
+            args,
+            base_commits,
+            head_commit,
+            files,
+        })
+    }
+}
+
+/// Gets the command line arguments to record, leaving out the program name
+/// and the manifest options themselves.
+pub fn recorded_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut env_args = std::env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        if arg == "--manifest" || arg == "--from-manifest" {
+            env_args.next();
+            continue;
+        }
+        args.push(arg);
+    }
+    args
+}
+
+/// Hashes a file the way git would, so that changes to it can be noticed.
+pub fn file_id(path: &Path) -> Result<git2::Oid> {
+    let contents = fs::read(path)
+        .with_context(|| format!("error reading {}", path.display()))?;
+    Ok(git2::Oid::hash_object(git2::ObjectType::Blob, &contents)?)
+}
+
+/// Formats a TOML basic string, whose escapes JSON strings share.
+fn toml_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+fn toml_array(values: &[String]) -> String {
+    let values = values
+        .iter()
+        .map(|value| toml_string(value))
+        .collect::<Vec<_>>();
+    format!("[{}]", values.join(", "))
+}
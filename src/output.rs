@@ -0,0 +1,1639 @@
+use crate::{
+    display_path,
+    pattern::Matcher,
+    scan::{Hunk, Usage},
+    status_char,
+    syntax::{Syntax, Token},
+    CaptureGroup,
+    Line,
+    LineKind,
+    COLOR,
+};
+use anyhow::{bail, Context, Error, Result};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    str,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+/// The version of the JSON output's schema, included in JSON and SARIF
+/// output.
+///
+/// Adding a field doesn't change the version, so parsers should ignore fields
+/// they don't know. Removing a field, renaming it, or changing its type or
+/// meaning does.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How a run was set up, recorded alongside its results in JSON and SARIF
+/// output so that they can be reproduced and audited later.
+pub struct Metadata<'a> {
+    /// the strategy that picked the diff bases, such as "merge-base", or
+    /// "manifest" when they came from one
+    pub base_strategy: &'a str,
+    /// the diff bases, with the empty tree for an empty base
+    pub base_commits: &'a [git2::Oid],
+    /// the commit scanned, or HEAD when scanning the working tree
+    pub head_commit: Option<git2::Oid>,
+    /// whether the working tree had uncommitted changes, or `None` when a
+    /// commit was scanned instead
+    pub dirty: Option<bool>,
+    /// the command line arguments, without the program name
+    pub args: &'a [String],
+}
+
+impl Metadata<'_> {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "base_strategy": self.base_strategy,
+            "base_commits": self
+                .base_commits
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>(),
+            "head_commit": self.head_commit.map(|id| id.to_string()),
+            "dirty": self.dirty,
+            "args": self.args,
+            "tool": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Format {
+    Text,
+    Table,
+    Badge,
+    Report,
+    Json,
+    Markdown,
+    Vimgrep,
+    Html,
+    Sarif,
+    Csv,
+    Tsv,
+    Teamcity,
+    Azure,
+}
+
+impl str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "table" => Ok(Self::Table),
+            "badge" => Ok(Self::Badge),
+            "report" => Ok(Self::Report),
+            "json" => Ok(Self::Json),
+            "markdown" => Ok(Self::Markdown),
+            "vimgrep" => Ok(Self::Vimgrep),
+            "html" => Ok(Self::Html),
+            "sarif" => Ok(Self::Sarif),
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            "teamcity" => Ok(Self::Teamcity),
+            "azure" => Ok(Self::Azure),
+            s => bail!("{:?} is not a valid output format", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GroupBy {
+    Match,
+    Rule,
+}
+
+impl str::FromStr for GroupBy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "match" => Ok(Self::Match),
+            "rule" => Ok(Self::Rule),
+            s => bail!("{:?} is not a valid grouping", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Status,
+    Path,
+    Line,
+    Rule,
+    Excerpt,
+    Fingerprint,
+}
+
+impl Column {
+    fn header(self) -> &'static str {
+        match self {
+            Self::Status => "STATUS",
+            Self::Path => "PATH",
+            Self::Line => "LINE",
+            Self::Rule => "RULE",
+            Self::Excerpt => "EXCERPT",
+            Self::Fingerprint => "FINGERPRINT",
+        }
+    }
+}
+
+impl str::FromStr for Column {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(Self::Status),
+            "path" => Ok(Self::Path),
+            "line" => Ok(Self::Line),
+            "rule" => Ok(Self::Rule),
+            "excerpt" => Ok(Self::Excerpt),
+            "fingerprint" => Ok(Self::Fingerprint),
+            s => bail!("{:?} is not a valid column", s),
+        }
+    }
+}
+
+/// A comma-separated list of table columns.
+#[derive(Debug)]
+pub struct Columns(pub Vec<Column>);
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self(vec![
+            Column::Path,
+            Column::Line,
+            Column::Rule,
+            Column::Excerpt,
+        ])
+    }
+}
+
+impl str::FromStr for Columns {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let columns = s
+            .split(',')
+            .map(|column| column.trim().parse())
+            .collect::<Result<Vec<_>>>()?;
+        if columns.is_empty() {
+            bail!("no columns given");
+        }
+        Ok(Self(columns))
+    }
+}
+
+/// A maximum width for a table column, given as `COLUMN=WIDTH`.
+#[derive(Debug)]
+pub struct ColumnWidth {
+    pub column: Column,
+    pub width: usize,
+}
+
+impl str::FromStr for ColumnWidth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, width) = s
+            .split_once('=')
+            .context("column width must be given as COLUMN=WIDTH")?;
+        let column = column.parse()?;
+        let width = width.parse().context("invalid column width")?;
+        if width == 0 {
+            bail!("column width must be greater than zero");
+        }
+        Ok(Self { column, width })
+    }
+}
+
+/// Prints lines as a table with aligned columns.
+///
+/// Cells wider than the max width for their column are truncated with an
+/// ellipsis. The last column is never padded.
+pub fn print_table(
+    lines: &[Line],
+    rules: &[String],
+    columns: &[Column],
+    max_widths: &[ColumnWidth],
+) {
+    let max_width = |column: Column| {
+        max_widths
+            .iter()
+            .rev()
+            .find(|width| width.column == column)
+            .map(|width| width.width)
+    };
+
+    let rows = lines
+        .iter()
+        .map(|line| {
+            columns
+                .iter()
+                .map(|&column| {
+                    let cell = match column {
+                        Column::Status => status_char(line.status).to_string(),
+                        Column::Path => display_path(&line.path),
+                        Column::Line => line.lineno.to_string(),
+                        Column::Rule => rules[line.rule].clone(),
+                        Column::Excerpt => line.content.clone(),
+                        Column::Fingerprint => line.fingerprint(rules),
+                    };
+                    match max_width(column) {
+                        Some(width) => truncate(cell, width),
+                        None => cell,
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(idx, column)| {
+            rows.iter()
+                .map(|row| row[idx].chars().count())
+                .chain([column.header().len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    let color = COLOR.load(Ordering::SeqCst);
+    let headers = columns
+        .iter()
+        .map(|&column| (column, column.header()))
+        .collect::<Vec<_>>();
+    println!("{}", format_row(&headers, &widths, color));
+    for row in &rows {
+        let cells = columns
+            .iter()
+            .zip(row)
+            .map(|(&column, cell)| (column, cell.as_str()))
+            .collect::<Vec<_>>();
+        println!("{}", format_row(&cells, &widths, color));
+    }
+}
+
+/// Badge colors to use based on the number of matches, given as a
+/// comma-separated list of `MIN_COUNT=COLOR`.
+#[derive(Debug)]
+pub struct BadgeColors(Vec<(usize, String)>);
+
+impl Default for BadgeColors {
+    fn default() -> Self {
+        Self(vec![
+            (0, "brightgreen".to_owned()),
+            (1, "yellow".to_owned()),
+            (10, "red".to_owned()),
+        ])
+    }
+}
+
+impl str::FromStr for BadgeColors {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = s
+            .split(',')
+            .map(|threshold| {
+                let (count, color) = threshold
+                    .split_once('=')
+                    .context("badge color must be given as MIN_COUNT=COLOR")?;
+                let count = count
+                    .trim()
+                    .parse()
+                    .context("invalid badge color count")?;
+                Ok((count, color.trim().to_owned()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        thresholds.sort_by_key(|(count, _)| *count);
+        Ok(Self(thresholds))
+    }
+}
+
+impl BadgeColors {
+    fn color(&self, count: usize) -> &str {
+        self.0
+            .iter()
+            .rev()
+            .find(|(min_count, _)| count >= *min_count)
+            .map(|(_, color)| color.as_str())
+            .unwrap_or("lightgrey")
+    }
+}
+
+/// Prints a shields.io endpoint badge describing the number of lines.
+pub fn print_badge(lines: &[Line], label: &str, colors: &BadgeColors) {
+    let badge = serde_json::json!({
+        "schemaVersion": 1,
+        "label": label,
+        "message": lines.len().to_string(),
+        "color": colors.color(lines.len()),
+    });
+    println!("{}", badge);
+}
+
+/// Prints lines as a SARIF 2.1.0 log with one result per line, using the
+/// search patterns as rule IDs.
+pub fn print_sarif(lines: &[Line], rules: &[String], metadata: &Metadata<'_>) {
+    let results = lines
+        .iter()
+        .map(|line| {
+            let rule = &rules[line.rule];
+            // SARIF paths are URIs, so they always use forward slashes
+            let uri = line.path.to_string_lossy().replace('\\', "/");
+            serde_json::json!({
+                "ruleId": rule,
+                "level": "warning",
+                "message": {
+                    "text": format!("added line matches {}", rule),
+                },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": {
+                            "uri": uri,
+                        },
+                        "region": {
+                            "startLine": line.lineno,
+                            "snippet": {
+                                "text": line.content,
+                            },
+                        },
+                    },
+                }],
+                "partialFingerprints": {
+                    "branchGrep/v1": line.fingerprint(rules),
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    let log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules
+                        .iter()
+                        .map(|rule| serde_json::json!({ "id": rule }))
+                        .collect::<Vec<_>>(),
+                },
+            },
+            "results": results,
+            "properties": {
+                "branchGrepSchemaVersion": SCHEMA_VERSION,
+                "branchGrepMetadata": metadata.to_json(),
+            },
+        }],
+    });
+    println!("{}", log);
+}
+
+/// Prints lines as TeamCity inspection service messages, declaring an
+/// inspection type for each rule first.
+pub fn print_teamcity(lines: &[Line], rules: &[String]) {
+    for (idx, rule) in rules.iter().enumerate() {
+        println!(
+            "##teamcity[inspectionType id='{}' name='{}' description='{}' \
+             category='branch-grep']",
+            teamcity_rule_id(idx),
+            escape_teamcity(rule),
+            escape_teamcity(&format!("added line matches {}", rule)),
+        );
+    }
+    for line in lines {
+        // TeamCity paths are relative to the checkout directory and always
+        // use forward slashes
+        let path = line.path.to_string_lossy().replace('\\', "/");
+        println!(
+            "##teamcity[inspection typeId='{}' message='{}' file='{}' \
+             line='{}' SEVERITY='WARNING']",
+            teamcity_rule_id(line.rule),
+            escape_teamcity(&line.content),
+            escape_teamcity(&path),
+            line.lineno,
+        );
+    }
+}
+
+fn teamcity_rule_id(rule: usize) -> String {
+    format!("branch-grep-{}", rule)
+}
+
+/// Escapes a value for a TeamCity service message attribute.
+fn escape_teamcity(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '|' => escaped.push_str("||"),
+            '\'' => escaped.push_str("|'"),
+            '\n' => escaped.push_str("|n"),
+            '\r' => escaped.push_str("|r"),
+            '[' => escaped.push_str("|["),
+            ']' => escaped.push_str("|]"),
+            c if !c.is_ascii() => {
+                escaped.push_str(&format!("|0x{:04x}", c as u32))
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Prints lines as Azure Pipelines logging commands, which annotate the
+/// pull request diff.
+pub fn print_azure(lines: &[Line], rules: &[String]) {
+    for line in lines {
+        let path = line.path.to_string_lossy().replace('\\', "/");
+        println!(
+            "##vso[task.logissue \
+             type=warning;sourcepath={};linenumber={};columnnumber={};\
+             code={}]{}",
+            escape_azure_property(&path),
+            line.lineno,
+            line.range().start + 1,
+            escape_azure_property(&rules[line.rule]),
+            escape_azure_message(&line.content),
+        );
+    }
+}
+
+/// Escapes the message of an Azure Pipelines logging command.
+fn escape_azure_message(s: &str) -> String {
+    s.replace('%', "%AZP25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property value of an Azure Pipelines logging command.
+fn escape_azure_property(s: &str) -> String {
+    escape_azure_message(s)
+        .replace(';', "%3B")
+        .replace(']', "%5D")
+}
+
+/// Prints lines as a JSON object with a summary and a record for each line.
+pub fn print_json(lines: &[Line], rules: &[String], metadata: &Metadata<'_>) {
+    let mut paths = lines.iter().map(|line| &line.path).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    let matches = lines
+        .iter()
+        .map(|line| {
+            serde_json::json!({
+                "path": display_path(&line.path),
+                "line": line.lineno,
+                "start": line.range().start,
+                "end": line.range().end,
+                "ranges": line
+                    .ranges
+                    .iter()
+                    .map(|range| serde_json::json!({
+                        "start": range.start,
+                        "end": range.end,
+                    }))
+                    .collect::<Vec<_>>(),
+                "content": line.content,
+                "rule": rules[line.rule],
+                "kind": match line.kind {
+                    LineKind::Added => "added",
+                    LineKind::Existing => "existing",
+                },
+                "status": status_char(line.status).to_string(),
+                "fingerprint": line.fingerprint(rules),
+            })
+        })
+        .collect::<Vec<_>>();
+    let output = serde_json::json!({
+        "schema_version": SCHEMA_VERSION,
+        "metadata": metadata.to_json(),
+        "summary": {
+            "rules": rules,
+            "matches": lines.len(),
+            "files": paths.len(),
+        },
+        "matches": matches,
+    });
+    println!("{}", output);
+}
+
+/// Prints the JSON Schema document describing JSON output.
+pub fn print_schema() {
+    let range = serde_json::json!({
+        "type": "object",
+        "required": ["start", "end"],
+        "properties": {
+            "start": { "type": "integer", "minimum": 0 },
+            "end": { "type": "integer", "minimum": 0 },
+        },
+    });
+    let schema = serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("{} JSON output", env!("CARGO_PKG_NAME")),
+        "description": format!(
+            "Output of --format json, schema version {}. Fields may be added \
+             without changing the version, but not removed or changed.",
+            SCHEMA_VERSION
+        ),
+        "type": "object",
+        "required": ["schema_version", "metadata", "summary", "matches"],
+        "properties": {
+            "schema_version": { "const": SCHEMA_VERSION },
+            "metadata": {
+                "type": "object",
+                "required": [
+                    "base_strategy",
+                    "base_commits",
+                    "head_commit",
+                    "dirty",
+                    "args",
+                    "tool",
+                ],
+                "properties": {
+                    "base_strategy": {
+                        "description": "how the diff bases were picked, a \
+                                        base strategy such as \"merge-base\", \
+                                        a fallback such as \"upstream \
+                                        fallback\", or \"manifest\"",
+                        "type": "string",
+                    },
+                    "base_commits": {
+                        "description": "the diff bases, with the empty \
+                                        tree's ID for an empty base",
+                        "type": "array",
+                        "items": { "type": "string" },
+                    },
+                    "head_commit": {
+                        "description": "the commit scanned, or HEAD when \
+                                        scanning the working tree",
+                        "type": ["string", "null"],
+                    },
+                    "dirty": {
+                        "description": "whether the working tree had \
+                                        uncommitted changes, or null when a \
+                                        commit was scanned instead",
+                        "type": ["boolean", "null"],
+                    },
+                    "args": {
+                        "description": "the command line arguments",
+                        "type": "array",
+                        "items": { "type": "string" },
+                    },
+                    "tool": {
+                        "type": "object",
+                        "required": ["name", "version"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "version": { "type": "string" },
+                        },
+                    },
+                },
+            },
+            "summary": {
+                "type": "object",
+                "required": ["rules", "matches", "files"],
+                "properties": {
+                    "rules": {
+                        "description": "the search patterns, one per rule",
+                        "type": "array",
+                        "items": { "type": "string" },
+                    },
+                    "matches": { "type": "integer", "minimum": 0 },
+                    "files": { "type": "integer", "minimum": 0 },
+                },
+            },
+            "matches": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": [
+                        "path",
+                        "line",
+                        "start",
+                        "end",
+                        "ranges",
+                        "content",
+                        "rule",
+                        "kind",
+                        "status",
+                        "fingerprint",
+                    ],
+                    "properties": {
+                        "path": { "type": "string" },
+                        "line": { "type": "integer", "minimum": 1 },
+                        "start": {
+                            "description": "byte offset of the first match",
+                            "type": "integer",
+                            "minimum": 0,
+                        },
+                        "end": { "type": "integer", "minimum": 0 },
+                        "ranges": {
+                            "description": "byte ranges of every match",
+                            "type": "array",
+                            "items": range,
+                        },
+                        "content": { "type": "string" },
+                        "rule": { "type": "string" },
+                        "kind": { "enum": ["added", "existing"] },
+                        "status": {
+                            "description": "the file's status letter, as \
+                                            in git diff --name-status",
+                            "type": "string",
+                        },
+                        "fingerprint": { "type": "string" },
+                    },
+                },
+            },
+        },
+    });
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&schema).expect("error printing schema")
+    );
+}
+
+/// Prints a GitHub-flavored Markdown report of lines, with a summary table
+/// followed by a section for each file.
+///
+/// Excerpts are written as HTML `<pre>` blocks rather than fenced code blocks
+/// so that the matching part can be bolded.
+pub fn print_markdown(
+    lines: &[Line],
+    rules: &[String],
+    hunks: &[Hunk],
+    context: usize,
+) {
+    let files = group_by_file(lines);
+
+    let rules_list = rules
+        .iter()
+        .map(|rule| format!("`` {} ``", rule))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("## Matches for {}", rules_list);
+    println!();
+    if lines.is_empty() {
+        println!("No matches.");
+        return;
+    }
+    println!("| File | Matches |");
+    println!("| --- | ---: |");
+    for (path, file_lines) in &files {
+        println!(
+            "| `{}` | {} |",
+            display_path(path).replace('|', "\\|"),
+            file_lines.len()
+        );
+    }
+    println!("| **Total** | **{}** |", lines.len());
+
+    for (path, file_lines) in &files {
+        println!();
+        println!("### `{}`", display_path(path));
+        println!();
+        if context > 0 {
+            for line in file_lines {
+                print_markdown_context(line, hunks, context);
+            }
+            continue;
+        }
+        println!("<pre>");
+        for line in file_lines {
+            println!("{}: {}", line.lineno, highlight_html(line, "b", None));
+        }
+        println!("</pre>");
+    }
+}
+
+/// Prints the diff around a line as a fenced code block, falling back to the
+/// bare line if its hunk wasn't kept.
+fn print_markdown_context(line: &Line, hunks: &[Hunk], context: usize) {
+    let diff_lines = hunks
+        .iter()
+        .filter(|hunk| hunk.path == line.path)
+        .find_map(|hunk| hunk.context(line.lineno, context));
+    println!("Line {}:", line.lineno);
+    println!();
+    // the fence must be longer than any run of backticks in the lines
+    let longest_backticks = diff_lines
+        .into_iter()
+        .flatten()
+        .map(|(_, _, content)| longest_run(content, '`'))
+        .chain([longest_run(&line.content, '`')])
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_backticks.max(2) + 1);
+    println!("{}diff", fence);
+    match diff_lines {
+        Some(diff_lines) => {
+            for (origin, _, content) in diff_lines {
+                println!("{}{}", origin, content);
+            }
+        },
+        None => println!("+{}", line.content),
+    }
+    println!("{}", fence);
+    println!();
+}
+
+fn longest_run(s: &str, c: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for sc in s.chars() {
+        if sc == c {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>branch-grep report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+summary { cursor: pointer; font-family: monospace; font-size: 1.1em; }
+details { margin: 0.5em 0; }
+table { border-collapse: collapse; margin: 0.5em 0 0.5em 1.5em; }
+td { font-family: monospace; padding: 0.1em 0.5em; white-space: pre; }
+td.lineno { color: #999; text-align: right; }
+mark { background: #fd5; font-weight: bold; }
+.kw { color: #a626a4; }
+.str { color: #50a14f; }
+.num { color: #986801; }
+.com { color: #8a8b91; font-style: italic; }
+.count { color: #999; }
+</style>
+</head>
+<body>
+"#;
+
+const HTML_FILTER_SCRIPT: &str = r#"<script>
+document.getElementById("filter").addEventListener("input", function () {
+  var filter = this.value.toLowerCase();
+  document.querySelectorAll("details").forEach(function (file) {
+    var shown = 0;
+    file.querySelectorAll("tr").forEach(function (row) {
+      var text = (file.dataset.path + " " + row.textContent).toLowerCase();
+      var show = text.indexOf(filter) !== -1;
+      row.hidden = !show;
+      if (show) shown++;
+    });
+    file.hidden = shown === 0;
+  });
+});
+</script>
+"#;
+
+/// Prints a standalone HTML report of lines, with a collapsible section for
+/// each file, syntax highlighting for the languages it knows, and a box for
+/// filtering the lines shown.
+pub fn print_html(lines: &[Line], rules: &[String]) {
+    let files = group_by_file(lines);
+
+    print!("{}", HTML_HEAD);
+    let rules_list = rules
+        .iter()
+        .map(|rule| format!("<code>{}</code>", escape_html(rule)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("<h1>Matches for {}</h1>", rules_list);
+    println!("<p>{} matches in {} files</p>", lines.len(), files.len());
+    println!(
+        r#"<p><input id="filter" type="search" placeholder="Filter"></p>"#
+    );
+    for (path, file_lines) in &files {
+        let escaped_path = escape_html(&display_path(path));
+        println!(r#"<details open data-path="{}">"#, escaped_path);
+        println!(
+            r#"<summary>{} <span class="count">({})</span></summary>"#,
+            escaped_path,
+            file_lines.len()
+        );
+        let syntax = Syntax::for_path(path);
+        println!("<table>");
+        for line in file_lines {
+            println!(
+                r#"<tr><td class="lineno">{}</td><td>{}</td></tr>"#,
+                line.lineno,
+                highlight_html(line, "mark", syntax)
+            );
+        }
+        println!("</table>");
+        println!("</details>");
+    }
+    print!("{}", HTML_FILTER_SCRIPT);
+    println!("</body>");
+    println!("</html>");
+}
+
+/// Names the rule a line matched, for showing after it when there are several
+/// rules to tell apart.
+fn rule_tag(line: &Line, rules: &[String], color: bool) -> String {
+    if rules.len() <= 1 {
+        String::new()
+    } else if color {
+        format!(" \x1b[35m[{}]\x1b[m", rules[line.rule])
+    } else {
+        format!(" [{}]", rules[line.rule])
+    }
+}
+
+/// Groups lines by their file, in the order each file first appears.
+fn group_by_file(lines: &[Line]) -> Vec<(&PathBuf, Vec<&Line>)> {
+    let mut files = Vec::<(&PathBuf, Vec<&Line>)>::new();
+    for line in lines {
+        match files.iter_mut().find(|(path, _)| **path == line.path) {
+            Some((_, file_lines)) => file_lines.push(line),
+            None => files.push((&line.path, vec![line])),
+        }
+    }
+    files
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes a line's content for HTML, wrapping each match in a tag and, given
+/// the file's syntax, its tokens in spans classed by their kind.
+fn highlight_html(line: &Line, tag: &str, syntax: Option<&Syntax>) -> String {
+    let content = &line.content;
+    let tokens =
+        syntax.map_or_else(Vec::new, |syntax| syntax.tokenize(content));
+    let mut html = String::new();
+    let mut end = 0;
+    for range in &line.ranges {
+        html.push_str(&tokens_html(content, end..range.start, &tokens));
+        html.push_str(&format!(
+            "<{}>{}</{}>",
+            tag,
+            tokens_html(content, range.clone(), &tokens),
+            tag
+        ));
+        end = range.end;
+    }
+    html.push_str(&tokens_html(content, end..content.len(), &tokens));
+    html
+}
+
+/// Escapes part of a line for HTML, wrapping the parts of tokens within it in
+/// spans.
+fn tokens_html(
+    content: &str,
+    range: Range<usize>,
+    tokens: &[(Range<usize>, Token)],
+) -> String {
+    let mut html = String::new();
+    let mut end = range.start;
+    for (token_range, token) in tokens {
+        let start = token_range.start.max(range.start);
+        let token_end = token_range.end.min(range.end);
+        if start >= token_end {
+            continue;
+        }
+        html.push_str(&escape_html(&content[end..start]));
+        html.push_str(&format!(
+            r#"<span class="{}">{}</span>"#,
+            token.class(),
+            escape_html(&content[start..token_end])
+        ));
+        end = token_end;
+    }
+    html.push_str(&escape_html(&content[end..range.end]));
+    html
+}
+
+/// Formats a line as `path:line:column:content`, which vim's default
+/// errorformat understands.
+///
+/// Like vim, columns count bytes starting from 1.
+fn vimgrep_line(line: &Line) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        display_path(&line.path),
+        line.lineno,
+        line.range().start + 1,
+        line.content
+    )
+}
+
+/// Prints lines in vim's errorformat.
+pub fn print_vimgrep(lines: &[Line]) {
+    for line in lines {
+        println!("{}", vimgrep_line(line));
+    }
+}
+
+/// Writes lines to a file in vim's errorformat.
+pub fn write_quickfix(path: &Path, lines: &[Line]) -> Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        writeln!(contents, "{}", vimgrep_line(line))?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Writes per-rule match counts and the run duration to a file in the
+/// Prometheus textfile collector format.
+///
+/// The file is written under a temporary name and then renamed so that the
+/// collector never reads it half-written.
+pub fn write_metrics(
+    path: &Path,
+    lines: &[Line],
+    rules: &[String],
+    elapsed: Duration,
+) -> Result<()> {
+    let mut contents = String::new();
+    writeln!(
+        contents,
+        "# HELP branch_grep_matches Number of matching added lines per rule."
+    )?;
+    writeln!(contents, "# TYPE branch_grep_matches gauge")?;
+    for (idx, rule) in rules.iter().enumerate() {
+        let count = lines.iter().filter(|line| line.rule == idx).count();
+        let rule = rule
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        writeln!(
+            contents,
+            "branch_grep_matches{{rule=\"{}\"}} {}",
+            rule, count
+        )?;
+    }
+    writeln!(
+        contents,
+        "# HELP branch_grep_run_duration_seconds How long the run took."
+    )?;
+    writeln!(contents, "# TYPE branch_grep_run_duration_seconds gauge")?;
+    writeln!(
+        contents,
+        "branch_grep_run_duration_seconds {}",
+        elapsed.as_secs_f64()
+    )?;
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Writes lines to a file as a Markdown task list, one unchecked task per
+/// line.
+pub fn write_checklist(path: &Path, lines: &[Line]) -> Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        writeln!(
+            contents,
+            "- [ ] {}:{} \u{2014} {}",
+            display_path(&line.path),
+            line.lineno,
+            line.content
+        )?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Prints lines as comma-separated values with a header row.
+///
+/// Fields are quoted as needed following RFC 4180.
+pub fn print_csv(lines: &[Line]) {
+    print_delimited(lines, ',', quote_csv);
+}
+
+fn quote_csv(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Prints lines as tab-separated values with a header row.
+///
+/// Tabs, newlines, and backslashes in fields are backslash-escaped.
+pub fn print_tsv(lines: &[Line]) {
+    print_delimited(lines, '\t', escape_tsv);
+}
+
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn print_delimited(
+    lines: &[Line],
+    delimiter: char,
+    escape: impl Fn(&str) -> String,
+) {
+    let print_row = |fields: &[&str]| {
+        let row = fields
+            .iter()
+            .map(|field| escape(field))
+            .collect::<Vec<_>>()
+            .join(&delimiter.to_string());
+        println!("{}", row);
+    };
+    print_row(&["path", "line", "column", "match", "content"]);
+    for line in lines {
+        print_row(&[
+            &display_path(&line.path),
+            &line.lineno.to_string(),
+            &(line.range().start + 1).to_string(),
+            &line.content[line.range()],
+            &line.content,
+        ]);
+    }
+}
+
+/// Prints lines under a heading for each file, with only line numbers shown
+/// on the lines themselves.
+pub fn print_with_headings(
+    lines: &[Line],
+    rules: &[String],
+    omitted_counts: &HashMap<PathBuf, usize>,
+) {
+    let color = COLOR.load(Ordering::SeqCst);
+    for (idx, (path, mut file_lines)) in
+        group_by_file(lines).into_iter().enumerate()
+    {
+        if idx > 0 {
+            println!();
+        }
+        let untracked = file_lines
+            .iter()
+            .any(|line| line.status == git2::Delta::Untracked);
+        let path_style = if untracked { "31;1" } else { "32;1" };
+        if color {
+            println!("\x1b[{}m{}\x1b[m", path_style, display_path(path));
+        } else {
+            println!("{}", display_path(path));
+        }
+        file_lines.sort_by_key(|line| line.lineno);
+        for line in file_lines {
+            println!("{}{}", line.without_path(), rule_tag(line, rules, color));
+        }
+        if let Some(count) = omitted_counts.get(path) {
+            if color {
+                println!("\x1b[90m... and {} more in this file\x1b[m", count);
+            } else {
+                println!("... and {} more in this file", count);
+            }
+        }
+    }
+}
+
+/// Prints lines as a Markdown list of release notes grouped by file.
+///
+/// If the search pattern has a capture group, the first group's text is used
+/// as the note, otherwise the whole line is.
+pub fn print_notes(lines: &[Line], matchers: &[Box<dyn Matcher>]) {
+    for (idx, (path, file_lines)) in
+        group_by_file(lines).into_iter().enumerate()
+    {
+        if idx > 0 {
+            println!();
+        }
+        println!("### {}", display_path(path));
+        println!();
+        for line in file_lines {
+            let note = matchers[line.rule]
+                .capture(&line.content, &CaptureGroup::Index(1))
+                .unwrap_or(&line.content)
+                .trim();
+            println!("- {}", note);
+        }
+    }
+}
+
+/// Prints each of the given paths on its own line.
+pub fn print_paths<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) {
+    let color = COLOR.load(Ordering::SeqCst);
+    for path in paths {
+        if color {
+            println!("\x1b[32m{}\x1b[m", display_path(path));
+        } else {
+            println!("{}", display_path(path));
+        }
+    }
+}
+
+/// Prints the number of lines in each file as `path:count`.
+pub fn print_counts(lines: &[Line]) {
+    let color = COLOR.load(Ordering::SeqCst);
+    for (path, file_lines) in group_by_file(lines) {
+        if color {
+            println!(
+                "\x1b[32m{}\x1b[m:{}",
+                display_path(path),
+                file_lines.len()
+            );
+        } else {
+            println!("{}:{}", display_path(path), file_lines.len());
+        }
+    }
+}
+
+/// Prints lines as NUL-separated fields, with each record also ended by a
+/// NUL byte.
+pub fn print_null_separated(lines: &[Line]) {
+    for line in lines {
+        print!(
+            "{}\0{}\0{}\0",
+            display_path(&line.path),
+            line.lineno,
+            line.content
+        );
+    }
+}
+
+/// Prints lines as text.
+///
+/// Files with lines left out get a note after their last line saying how
+/// many more there were.
+pub fn print_lines(
+    lines: &[Line],
+    rules: &[String],
+    omitted_counts: &HashMap<PathBuf, usize>,
+) {
+    let mut last_lines = HashMap::new();
+    for (idx, line) in lines.iter().enumerate() {
+        last_lines.insert(&line.path, idx);
+    }
+    let color = COLOR.load(Ordering::SeqCst);
+    for (idx, line) in lines.iter().enumerate() {
+        println!("{}{}", line, rule_tag(line, rules, color));
+        if last_lines[&line.path] != idx {
+            continue;
+        }
+        if let Some(count) = omitted_counts.get(&line.path) {
+            let path = display_path(&line.path);
+            if color {
+                println!(
+                    "\x1b[32m{}\x1b[m: \x1b[90m... and {} more in this \
+                     file\x1b[m",
+                    path, count
+                );
+            } else {
+                println!("{}: ... and {} more in this file", path, count);
+            }
+        }
+    }
+}
+
+/// Prints a human-readable report of lines, with per-rule counts and a final
+/// verdict.
+///
+/// The run fails if there are any lines or if any budgets were exceeded.
+/// Returns whether it passed.
+pub fn print_report(
+    lines: &[Line],
+    rules: &[String],
+    exceeded_budgets: &[String],
+) -> bool {
+    let color = COLOR.load(Ordering::SeqCst);
+    for line in lines {
+        if color {
+            println!("\x1b[33m⚠\x1b[m {}", line);
+        } else {
+            println!("⚠ {}", line);
+        }
+    }
+    if !lines.is_empty() {
+        println!();
+    }
+
+    let width = rules
+        .iter()
+        .map(|rule| rule.chars().count())
+        .chain(["RULE".len()])
+        .max()
+        .unwrap_or(0);
+    if color {
+        println!(
+            "\x1b[1m{:<width$}\x1b[m  \x1b[1mMATCHES\x1b[m",
+            "RULE",
+            width = width
+        );
+    } else {
+        println!("{:<width$}  MATCHES", "RULE", width = width);
+    }
+    for (idx, rule) in rules.iter().enumerate() {
+        let count = lines.iter().filter(|line| line.rule == idx).count();
+        let padding = " ".repeat(width - rule.chars().count());
+        if color {
+            println!("\x1b[35m{}\x1b[m{}  {}", rule, padding, count);
+        } else {
+            println!("{}{}  {}", rule, padding, count);
+        }
+    }
+    for budget in exceeded_budgets {
+        if color {
+            println!("\x1b[31m✖\x1b[m budget exceeded for {}", budget);
+        } else {
+            println!("✖ budget exceeded for {}", budget);
+        }
+    }
+    println!();
+
+    let passed = lines.is_empty() && exceeded_budgets.is_empty();
+    let verdict = if passed {
+        ("\x1b[32;1m", "✔ PASS")
+    } else {
+        ("\x1b[31;1m", "✖ FAIL")
+    };
+    let summary = match lines.len() {
+        0 => "no matches".to_owned(),
+        1 => "1 match".to_owned(),
+        n => format!("{} matches", n),
+    };
+    if color {
+        println!("{}{}\x1b[m: {}", verdict.0, verdict.1, summary);
+    } else {
+        println!("{}: {}", verdict.1, summary);
+    }
+    passed
+}
+
+/// Prints each distinct matched string once, followed by the lines it was
+/// found on.
+///
+/// Groups are ordered by descending number of lines, and lines within a
+/// group keep their original order.
+pub fn print_grouped_by_match(lines: &[Line]) {
+    let mut groups = Vec::<(&str, Vec<&Line>)>::new();
+    for line in lines {
+        let r#match = &line.content[line.range()];
+        match groups.iter_mut().find(|(text, _)| *text == r#match) {
+            Some((_, group)) => group.push(line),
+            None => groups.push((r#match, vec![line])),
+        }
+    }
+    groups.sort_by(|(a_text, a_lines), (b_text, b_lines)| {
+        b_lines.len().cmp(&a_lines.len()).then(a_text.cmp(b_text))
+    });
+
+    let color = COLOR.load(Ordering::SeqCst);
+    for (text, group) in groups {
+        if color {
+            println!("\x1b[36;1m{}\x1b[m ({})", text, group.len());
+        } else {
+            println!("{} ({})", text, group.len());
+        }
+        for line in group {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Prints each rule as a heading with its number of matches, followed by the
+/// lines it matched.
+pub fn print_grouped_by_rule(lines: &[Line], rules: &[String]) {
+    let color = COLOR.load(Ordering::SeqCst);
+    for (idx, rule) in rules.iter().enumerate() {
+        let rule_lines = lines
+            .iter()
+            .filter(|line| line.rule == idx)
+            .collect::<Vec<_>>();
+        if rule_lines.is_empty() {
+            continue;
+        }
+        if color {
+            println!("\x1b[35;1m{}\x1b[m ({})", rule, rule_lines.len());
+        } else {
+            println!("{} ({})", rule, rule_lines.len());
+        }
+        for line in rule_lines {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Prints a frequency table of values, which must already be sorted.
+pub fn print_histogram(histogram: &[(String, usize)]) {
+    let width = histogram
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let color = COLOR.load(Ordering::SeqCst);
+    for (value, count) in histogram {
+        if color {
+            println!(
+                "{:>width$}  \x1b[36;1m{}\x1b[m",
+                count,
+                value,
+                width = width
+            );
+        } else {
+            println!("{:>width$}  {}", count, value, width = width);
+        }
+    }
+}
+
+/// Prints a histogram as a JSON object with an array of values and their
+/// counts, in the same order as [`print_histogram`].
+pub fn print_histogram_json(histogram: &[(String, usize)]) {
+    let histogram = histogram
+        .iter()
+        .map(|(value, count)| {
+            serde_json::json!({
+                "value": value,
+                "count": count,
+            })
+        })
+        .collect::<Vec<_>>();
+    let output = serde_json::json!({
+        "schema_version": SCHEMA_VERSION,
+        "histogram": histogram,
+    });
+    println!("{}", output);
+}
+
+/// Prints the number of added and removed lines for each language, like
+/// `+12 -3  rust`.
+pub fn print_languages(languages: &[(String, usize, usize)]) {
+    let added_width = languages
+        .iter()
+        .map(|(_, added, _)| added.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let removed_width = languages
+        .iter()
+        .map(|(_, _, removed)| removed.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let color = COLOR.load(Ordering::SeqCst);
+    for (language, added, removed) in languages {
+        if color {
+            println!(
+                "\x1b[32m+{:<added_width$}\x1b[m \
+                 \x1b[31m-{:<removed_width$}\x1b[m  {}",
+                added,
+                removed,
+                language,
+                added_width = added_width,
+                removed_width = removed_width,
+            );
+        } else {
+            println!(
+                "+{:<added_width$} -{:<removed_width$}  {}",
+                added,
+                removed,
+                language,
+                added_width = added_width,
+                removed_width = removed_width,
+            );
+        }
+    }
+}
+
+/// A file changed by the diff.
+pub struct ChangedFile {
+    pub status: char,
+    pub path: PathBuf,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Summary statistics about a run.
+pub struct Stats {
+    pub files_changed: usize,
+    pub files_with_matches: usize,
+    pub matches: usize,
+    pub cancelled: usize,
+    pub usage: Usage,
+    pub elapsed: Duration,
+}
+
+/// Prints summary statistics about a run.
+pub fn print_stats(stats: &Stats) {
+    let rows = [
+        ("files changed", stats.files_changed.to_string()),
+        ("files with matches", stats.files_with_matches.to_string()),
+        ("matches", stats.matches.to_string()),
+        ("cancelled by removed lines", stats.cancelled.to_string()),
+        ("blobs loaded", stats.usage.blobs_loaded.to_string()),
+        ("bytes diffed", format_bytes(stats.usage.bytes_diffed)),
+        ("lines scanned", stats.usage.lines_scanned.to_string()),
+        ("elapsed", format!("{:.3}s", stats.elapsed.as_secs_f32())),
+        (
+            "peak memory",
+            peak_rss().map_or_else(|| "unknown".to_owned(), format_bytes),
+        ),
+    ];
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let color = COLOR.load(Ordering::SeqCst);
+    for (name, value) in rows {
+        if color {
+            println!("\x1b[1m{:<width$}\x1b[m  {}", name, value, width = width);
+        } else {
+            println!("{:<width$}  {}", name, value, width = width);
+        }
+    }
+}
+
+/// Gets the peak resident set size of this process in bytes, where the
+/// platform reports it.
+fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Formats a number of bytes with a binary unit suffix.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Prints changed files with their status and line counts.
+pub fn print_changed_files(files: &[ChangedFile]) {
+    let added_width = files
+        .iter()
+        .map(|file| file.added.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let removed_width = files
+        .iter()
+        .map(|file| file.removed.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let color = COLOR.load(Ordering::SeqCst);
+    for file in files {
+        let path = display_path(&file.path);
+        if color {
+            println!(
+                "{}  \x1b[32m+{:<added_width$}\x1b[m \
+                 \x1b[31m-{:<removed_width$}\x1b[m  {}",
+                file.status,
+                file.added,
+                file.removed,
+                path,
+                added_width = added_width,
+                removed_width = removed_width,
+            );
+        } else {
+            println!(
+                "{}  +{:<added_width$} -{:<removed_width$}  {}",
+                file.status,
+                file.added,
+                file.removed,
+                path,
+                added_width = added_width,
+                removed_width = removed_width,
+            );
+        }
+    }
+}
+
+fn format_row(
+    cells: &[(Column, &str)],
+    widths: &[usize],
+    color: bool,
+) -> String {
+    let mut row = String::new();
+    for (idx, &(column, cell)) in cells.iter().enumerate() {
+        if idx > 0 {
+            row.push_str("  ");
+        }
+        let style = match column {
+            _ if !color => None,
+            Column::Status => None,
+            Column::Path => Some("32"),
+            Column::Line => Some("33"),
+            Column::Rule => Some("35"),
+            Column::Excerpt => None,
+            Column::Fingerprint => Some("2"),
+        };
+        match style {
+            Some(style) => {
+                row.push_str(&format!("\x1b[{}m{}\x1b[m", style, cell))
+            },
+            None => row.push_str(cell),
+        }
+        if idx + 1 < cells.len() {
+            let padding = widths[idx] - cell.chars().count();
+            row.extend(std::iter::repeat(' ').take(padding));
+        }
+    }
+    row
+}
+
+fn truncate(cell: String, width: usize) -> String {
+    if cell.chars().count() <= width {
+        return cell;
+    }
+    let mut truncated = cell.chars().take(width - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_teamcity_special_characters() {
+        assert_eq!(escape_teamcity(""), "");
+        assert_eq!(escape_teamcity("plain text"), "plain text");
+        assert_eq!(escape_teamcity("a|b"), "a||b");
+        assert_eq!(escape_teamcity("it's"), "it|'s");
+        assert_eq!(escape_teamcity("[x]"), "|[x|]");
+        assert_eq!(escape_teamcity("a\r\nb"), "a|r|nb");
+        assert_eq!(escape_teamcity("||"), "||||");
+    }
+
+    #[test]
+    fn escapes_non_ascii_for_teamcity() {
+        assert_eq!(escape_teamcity("é"), "|0x00e9");
+        assert_eq!(escape_teamcity("a→b"), "a|0x2192b");
+    }
+
+    #[test]
+    fn escapes_azure_messages() {
+        assert_eq!(escape_azure_message(""), "");
+        assert_eq!(escape_azure_message("100%"), "100%AZP25");
+        assert_eq!(escape_azure_message("a\r\nb"), "a%0D%0Ab");
+        // only properties need their separators escaped
+        assert_eq!(escape_azure_message("a;b]"), "a;b]");
+        // escapes aren't decoded again, so a literal escape stays literal
+        assert_eq!(escape_azure_message("%0A"), "%AZP250A");
+    }
+
+    #[test]
+    fn escapes_azure_properties() {
+        assert_eq!(escape_azure_property("src/a.rs"), "src/a.rs");
+        assert_eq!(escape_azure_property("a;b"), "a%3Bb");
+        assert_eq!(escape_azure_property("[rule]"), "[rule%5D");
+        assert_eq!(escape_azure_property("50%;\n"), "50%AZP25%3B%0A");
+    }
+
+    #[test]
+    fn quotes_csv_fields_only_when_needed() {
+        assert_eq!(quote_csv(""), "");
+        assert_eq!(quote_csv("plain"), "plain");
+        assert_eq!(quote_csv("tab\tand 'quote'"), "tab\tand 'quote'");
+        assert_eq!(quote_csv("a,b"), r#""a,b""#);
+        assert_eq!(quote_csv(r#"say "hi""#), r#""say ""hi""""#);
+        assert_eq!(quote_csv(r#"""#), r#""""""#);
+        assert_eq!(quote_csv("a\nb"), "\"a\nb\"");
+        assert_eq!(quote_csv("a\r\nb"), "\"a\r\nb\"");
+    }
+
+    #[test]
+    fn escapes_tsv_separators() {
+        assert_eq!(escape_tsv(""), "");
+        assert_eq!(escape_tsv(r#"a,"b""#), r#"a,"b""#);
+        assert_eq!(escape_tsv("a\tb"), r"a\tb");
+        assert_eq!(escape_tsv("a\r\nb"), r"a\r\nb");
+        // backslashes are escaped first, so a literal backslash followed by
+        // "t" can't be mistaken for an escaped tab
+        assert_eq!(escape_tsv(r"C:\tmp"), r"C:\\tmp");
+        assert_eq!(escape_tsv("\\\t"), r"\\\t");
+    }
+}
@@ -0,0 +1,325 @@
+use crate::CaptureGroup;
+#[cfg(not(feature = "pcre2"))]
+use anyhow::bail;
+use anyhow::Result;
+use regex::Regex;
+#[cfg(feature = "pcre2")]
+use std::cell::Cell;
+use std::ops::Range;
+
+/// A compiled search pattern, implemented by each regex engine.
+pub trait Matcher {
+    /// The pattern the matcher was compiled from.
+    fn as_str(&self) -> &str;
+
+    /// Finds the first match in some text at or after a byte offset.
+    fn find_at(&self, content: &str, start: usize) -> Option<Range<usize>>;
+
+    /// Gets the text of a capture group from the first match in some text.
+    fn capture<'t>(
+        &self,
+        content: &'t str,
+        group: &CaptureGroup,
+    ) -> Option<&'t str>;
+
+    /// Whether the pattern has a capture group.
+    fn has_group(&self, group: &CaptureGroup) -> bool;
+
+    /// How many times matching gave up after hitting a resource limit, which
+    /// is treated the same as not matching.
+    fn limit_hits(&self) -> usize {
+        0
+    }
+}
+
+impl Matcher for Regex {
+    fn as_str(&self) -> &str {
+        self.as_str()
+    }
+
+    fn find_at(&self, content: &str, start: usize) -> Option<Range<usize>> {
+        self.find_at(content, start).map(|r#match| r#match.range())
+    }
+
+    fn capture<'t>(
+        &self,
+        content: &'t str,
+        group: &CaptureGroup,
+    ) -> Option<&'t str> {
+        let captures = self.captures(content)?;
+        match group {
+            CaptureGroup::Index(idx) => captures.get(*idx),
+            CaptureGroup::Name(name) => captures.name(name),
+        }
+        .map(|r#match| r#match.as_str())
+    }
+
+    fn has_group(&self, group: &CaptureGroup) -> bool {
+        match group {
+            CaptureGroup::Index(idx) => *idx < self.captures_len(),
+            CaptureGroup::Name(name) => {
+                self.capture_names().flatten().any(|n| n == name)
+            },
+        }
+    }
+}
+
+/// A pattern compiled with PCRE2, which backtracks and so is compiled with
+/// limits on how long it can spend matching a line.
+#[cfg(feature = "pcre2")]
+struct Pcre2Matcher {
+    regex: pcre2::bytes::Regex,
+    /// the pattern without the limits prepended to it
+    pattern: String,
+    limit_hits: Cell<usize>,
+}
+
+#[cfg(feature = "pcre2")]
+impl Pcre2Matcher {
+    fn record_error(&self, error: pcre2::Error) {
+        // matching only fails when PCRE2 hits one of its resource limits
+        debug!("error matching {:?}: {}", self.pattern, error);
+        self.limit_hits.set(self.limit_hits.get() + 1);
+    }
+}
+
+#[cfg(feature = "pcre2")]
+impl Matcher for Pcre2Matcher {
+    fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    fn find_at(&self, content: &str, start: usize) -> Option<Range<usize>> {
+        match self.regex.find_at(content.as_bytes(), start) {
+            Ok(r#match) => {
+                r#match.map(|r#match| r#match.start()..r#match.end())
+            },
+            Err(error) => {
+                self.record_error(error);
+                None
+            },
+        }
+    }
+
+    fn capture<'t>(
+        &self,
+        content: &'t str,
+        group: &CaptureGroup,
+    ) -> Option<&'t str> {
+        let captures = match self.regex.captures(content.as_bytes()) {
+            Ok(captures) => captures?,
+            Err(error) => {
+                self.record_error(error);
+                return None;
+            },
+        };
+        let r#match = match group {
+            CaptureGroup::Index(idx) => captures.get(*idx),
+            CaptureGroup::Name(name) => captures.name(name),
+        }?;
+        // the pattern is compiled in UTF mode, so matches always fall on
+        // character boundaries
+        Some(&content[r#match.start()..r#match.end()])
+    }
+
+    fn has_group(&self, group: &CaptureGroup) -> bool {
+        match group {
+            CaptureGroup::Index(idx) => *idx < self.regex.captures_len(),
+            CaptureGroup::Name(name) => self
+                .regex
+                .capture_names()
+                .iter()
+                .flatten()
+                .any(|n| n == name),
+        }
+    }
+
+    fn limit_hits(&self) -> usize {
+        self.limit_hits.get()
+    }
+}
+
+/// Compiles a pattern with PCRE2, which supports look-around and
+/// backreferences.
+///
+/// The match limit caps how many backtracking steps matching one line can
+/// take, and the depth of its backtracking.
+#[cfg(feature = "pcre2")]
+pub fn build_pcre2(
+    pattern: &str,
+    case_insensitive: bool,
+    multiline: bool,
+    match_limit: u32,
+) -> Result<Box<dyn Matcher>> {
+    // the crate doesn't expose PCRE2's match context, but limits set at the
+    // start of a pattern can lower its defaults
+    let limited_pattern = format!(
+        "(*LIMIT_MATCH={0})(*LIMIT_DEPTH={0}){1}",
+        match_limit, pattern
+    );
+    let regex = pcre2::bytes::RegexBuilder::new()
+        .utf(true)
+        .jit_if_available(true)
+        .caseless(case_insensitive)
+        .multi_line(multiline)
+        .dotall(multiline)
+        .build(&limited_pattern)?;
+    Ok(Box::new(Pcre2Matcher {
+        regex,
+        pattern: pattern.to_owned(),
+        limit_hits: Cell::new(0),
+    }))
+}
+
+#[cfg(not(feature = "pcre2"))]
+pub fn build_pcre2(
+    _pattern: &str,
+    _case_insensitive: bool,
+    _multiline: bool,
+    _match_limit: u32,
+) -> Result<Box<dyn Matcher>> {
+    bail!("PCRE2 support was not enabled when building git-branch-grep");
+}
+
+/// The search patterns, each of which is a rule that lines can match.
+pub struct Patterns {
+    matchers: Vec<Box<dyn Matcher>>,
+    rules: Vec<String>,
+}
+
+impl Patterns {
+    pub fn new(matchers: Vec<Box<dyn Matcher>>) -> Self {
+        let rules = matchers
+            .iter()
+            .map(|matcher| matcher.as_str().to_owned())
+            .collect();
+        Self { matchers, rules }
+    }
+
+    /// The source of each pattern, indexed by rule.
+    pub fn rules(&self) -> &[String] {
+        &self.rules
+    }
+
+    pub fn matchers(&self) -> &[Box<dyn Matcher>] {
+        &self.matchers
+    }
+
+    /// Finds the leftmost match of any pattern in a line, along with the rule
+    /// that matched.
+    ///
+    /// Earlier rules win when several match at the same place.
+    pub fn find(&self, content: &str) -> Option<(Range<usize>, usize)> {
+        self.find_at(content, 0)
+    }
+
+    /// Like [`Patterns::find`], but also finds every later match of the same
+    /// pattern in the line.
+    pub fn find_all(
+        &self,
+        content: &str,
+    ) -> Option<(Vec<Range<usize>>, usize)> {
+        let (first, rule) = self.find(content)?;
+        let matcher = &self.matchers[rule];
+        let mut ranges = vec![first];
+        loop {
+            let last = ranges.last().expect("no first match");
+            let start = if last.is_empty() {
+                // step past empty matches so the search makes progress
+                match content[last.end..].chars().next() {
+                    Some(c) => last.end + c.len_utf8(),
+                    None => break,
+                }
+            } else {
+                last.end
+            };
+            match matcher.find_at(content, start) {
+                Some(range) => ranges.push(range),
+                None => break,
+            }
+        }
+        Some((ranges, rule))
+    }
+
+    /// Like [`Patterns::find`], but starting the search at some byte offset.
+    pub fn find_at(
+        &self,
+        content: &str,
+        start: usize,
+    ) -> Option<(Range<usize>, usize)> {
+        self.matchers
+            .iter()
+            .enumerate()
+            .filter_map(|(rule, matcher)| {
+                matcher.find_at(content, start).map(|range| (range, rule))
+            })
+            .min_by_key(|(range, rule)| (range.start, *rule))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(patterns: &[&str]) -> Patterns {
+        Patterns::new(
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Box::new(Regex::new(pattern).unwrap()) as Box<dyn Matcher>
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn finds_the_leftmost_match_of_any_rule() {
+        let patterns = build(&["FIXME", "TODO"]);
+        assert_eq!(patterns.find("a TODO then FIXME"), Some((2..6, 1)));
+        assert_eq!(patterns.find("a FIXME then TODO"), Some((2..7, 0)));
+        assert_eq!(patterns.find("nothing"), None);
+    }
+
+    #[test]
+    fn prefers_earlier_rules_at_the_same_start() {
+        // the later rule's match is longer, but starts at the same place
+        let patterns = build(&["TO", "TODO"]);
+        assert_eq!(patterns.find("x TODO"), Some((2..4, 0)));
+        let patterns = build(&["TODO", "TO"]);
+        assert_eq!(patterns.find("x TODO"), Some((2..6, 0)));
+    }
+
+    #[test]
+    fn finds_overlapping_rules_from_an_offset() {
+        let patterns = build(&["TODO", "DO"]);
+        assert_eq!(patterns.find_at("TODO", 0), Some((0..4, 0)));
+        // the first rule's only match starts before the offset
+        assert_eq!(patterns.find_at("TODO", 1), Some((2..4, 1)));
+        assert_eq!(patterns.find_at("TODO", 4), None);
+    }
+
+    #[test]
+    fn finds_all_matches_of_the_first_rule_only() {
+        let patterns = build(&["TODO", "FIXME"]);
+        assert_eq!(
+            patterns.find_all("TODO FIXME TODO"),
+            Some((vec![0..4, 11..15], 0))
+        );
+        assert_eq!(patterns.find_all("FIXME"), Some((vec![0..5], 1)));
+        assert_eq!(patterns.find_all(""), None);
+    }
+
+    #[test]
+    fn steps_past_zero_width_matches() {
+        let patterns = build(&[r"\b"]);
+        assert_eq!(
+            patterns.find_all("ab cd!"),
+            Some((vec![0..0, 2..2, 3..3, 5..5], 0))
+        );
+        assert_eq!(patterns.find_all(""), None);
+        let patterns = build(&["x*"]);
+        assert_eq!(patterns.find_all(""), Some((vec![0..0], 0)));
+        // é is two bytes, and the search mustn't split it
+        assert_eq!(patterns.find_all("éé"), Some((vec![0..0, 2..2, 4..4], 0)));
+    }
+}
@@ -0,0 +1,1123 @@
+use crate::{
+    diff_options,
+    encoding,
+    find_match,
+    pattern::Patterns,
+    workdir_path,
+    Line,
+    LineKind,
+    MultiSet,
+    DEBUG,
+};
+use anyhow::{bail, Context, Error, Result};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    str,
+    sync::atomic::Ordering,
+};
+
+/// What to do with files that look binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryMode {
+    /// leave them out of the scan
+    Skip,
+    /// search them as text, replacing invalid UTF-8
+    Text,
+    /// search them as text, but only report which files matched
+    Report,
+}
+
+impl str::FromStr for BinaryMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "text" => Ok(Self::Text),
+            "report" => Ok(Self::Report),
+            s => bail!("{:?} is not a valid binary mode", s),
+        }
+    }
+}
+
+/// Which kinds of changed files to scan, given like git's `--diff-filter` as
+/// status letters, uppercase to select only those statuses and lowercase to
+/// leave them out.
+#[derive(Debug, Default)]
+pub struct DiffFilter {
+    included: Vec<git2::Delta>,
+    excluded: Vec<git2::Delta>,
+}
+
+impl str::FromStr for DiffFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        for c in s.chars() {
+            // untracked files are added as far as the branch is concerned
+            let statuses: &[git2::Delta] = match c.to_ascii_uppercase() {
+                'A' => &[git2::Delta::Added, git2::Delta::Untracked],
+                'C' => &[git2::Delta::Copied],
+                'D' => &[git2::Delta::Deleted],
+                'M' => &[git2::Delta::Modified],
+                'R' => &[git2::Delta::Renamed],
+                'T' => &[git2::Delta::Typechange],
+                'U' => &[git2::Delta::Conflicted],
+                _ => bail!("{:?} is not a valid diff filter status", c),
+            };
+            if c.is_ascii_uppercase() {
+                filter.included.extend(statuses);
+            } else {
+                filter.excluded.extend(statuses);
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl DiffFilter {
+    fn matches(&self, status: git2::Delta) -> bool {
+        (self.included.is_empty() || self.included.contains(&status))
+            && !self.excluded.contains(&status)
+    }
+}
+
+pub struct ScanOptions<'a> {
+    /// the pattern to search with, if any
+    pub search: Option<&'a Patterns>,
+    /// whether a path should be left out of the scan entirely
+    pub is_excluded: &'a dyn Fn(&Path) -> bool,
+    /// whether to look for lines which don't match the search pattern instead
+    pub invert: bool,
+    /// whether to match against all of the added or removed lines in a hunk
+    /// at once instead of line by line
+    pub multiline: bool,
+    /// whether to record errors with individual files and skip them rather
+    /// than failing the whole scan
+    pub keep_going: bool,
+    /// what to do with binary files, which must be diffed as text unless
+    /// they're skipped
+    pub binary: BinaryMode,
+    /// the size in bytes above which files are left out of the scan
+    pub max_filesize: Option<u64>,
+    /// which kinds of changed files to scan
+    pub diff_filter: Option<&'a DiffFilter>,
+    /// whether to match unchanged context lines as well as changed ones
+    pub match_context: bool,
+    /// whether to keep the lines of each hunk, for showing results in
+    /// context
+    pub keep_hunks: bool,
+    /// whether only the paths of files with matches are needed, so a file's
+    /// added lines can stop being matched once it has a match
+    pub first_match_per_file: bool,
+}
+
+/// The results of scanning a diff.
+pub struct Scan {
+    /// matching added lines which weren't cancelled out by removed lines
+    pub lines: Vec<Line>,
+    /// the new-side blob ID and status of each file with added lines
+    pub touched_paths: HashMap<PathBuf, (git2::Oid, git2::Delta)>,
+    /// the number of added and removed lines in each file
+    pub line_counts: HashMap<PathBuf, (usize, usize)>,
+    /// the number of matching added lines cancelled out by removed lines
+    pub cancelled_count: usize,
+    /// files skipped because of errors, when keeping going
+    pub errors: Vec<(PathBuf, Error)>,
+    /// binary files with matching added lines, when only reporting them
+    pub binary_matches: Vec<PathBuf>,
+    /// the lines of each hunk, when keeping them
+    pub hunks: Vec<Hunk>,
+    /// how much work the scan took
+    pub usage: Usage,
+}
+
+/// Counts of the work done scanning diffs, for reporting with stats.
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    /// the number of file versions loaded to diff, from the object database
+    /// or the working tree
+    pub blobs_loaded: usize,
+    /// the total size of the lines of the diff
+    pub bytes_diffed: u64,
+    /// the number of added, removed, and context lines looked at
+    pub lines_scanned: usize,
+}
+
+impl Usage {
+    /// Adds the work done scanning another diff to this.
+    fn add(&mut self, other: Usage) {
+        self.blobs_loaded += other.blobs_loaded;
+        self.bytes_diffed += other.bytes_diffed;
+        self.lines_scanned += other.lines_scanned;
+    }
+}
+
+/// The lines of a hunk, kept for showing results in context.
+pub struct Hunk {
+    pub path: PathBuf,
+    /// the origin (`+`, `-`, or a space), new line number, and content of
+    /// each line
+    pub lines: Vec<(char, Option<u32>, String)>,
+}
+
+impl Hunk {
+    /// Gets the lines around an added line, up to some number of lines on
+    /// each side.
+    pub fn context(
+        &self,
+        lineno: u32,
+        context: usize,
+    ) -> Option<&[(char, Option<u32>, String)]> {
+        let idx = self.lines.iter().position(|(origin, new_lineno, _)| {
+            *origin == '+' && *new_lineno == Some(lineno)
+        })?;
+        let start = idx.saturating_sub(context);
+        let end = (idx + context + 1).min(self.lines.len());
+        Some(&self.lines[start..end])
+    }
+}
+
+impl Scan {
+    /// Merges another scan into this one, keeping lines found by either.
+    pub fn union(&mut self, other: Scan) {
+        let keys = self
+            .lines
+            .iter()
+            .map(|line| line.key())
+            .collect::<HashSet<_>>();
+        let lines = other
+            .lines
+            .into_iter()
+            .filter(|line| !keys.contains(&line.key()))
+            .collect::<Vec<_>>();
+        self.lines.extend(lines);
+        self.usage.add(other.usage);
+        self.merge_metadata(
+            other.touched_paths,
+            other.line_counts,
+            other.cancelled_count,
+            other.errors,
+            other.binary_matches,
+            other.hunks,
+        );
+    }
+
+    /// Merges another scan into this one, keeping only lines found by both.
+    pub fn intersection(&mut self, other: Scan) {
+        let keys = other
+            .lines
+            .iter()
+            .map(|line| line.key())
+            .collect::<HashSet<_>>();
+        self.lines.retain(|line| keys.contains(&line.key()));
+        self.usage.add(other.usage);
+        self.merge_metadata(
+            other.touched_paths,
+            other.line_counts,
+            other.cancelled_count,
+            other.errors,
+            other.binary_matches,
+            other.hunks,
+        );
+    }
+
+    fn merge_metadata(
+        &mut self,
+        touched_paths: HashMap<PathBuf, (git2::Oid, git2::Delta)>,
+        line_counts: HashMap<PathBuf, (usize, usize)>,
+        cancelled_count: usize,
+        errors: Vec<(PathBuf, Error)>,
+        binary_matches: Vec<PathBuf>,
+        hunks: Vec<Hunk>,
+    ) {
+        for (path, touched) in touched_paths {
+            self.touched_paths.entry(path).or_insert(touched);
+        }
+        for (path, counts) in line_counts {
+            self.line_counts.entry(path).or_insert(counts);
+        }
+        self.cancelled_count = self.cancelled_count.max(cancelled_count);
+        for (path, error) in errors {
+            if !self.errors.iter().any(|(p, _)| *p == path) {
+                self.errors.push((path, error));
+            }
+        }
+        for path in binary_matches {
+            if !self.binary_matches.contains(&path) {
+                self.binary_matches.push(path);
+            }
+        }
+        // other diff bases give other hunks for the same files, but one set
+        // of hunks per file is enough for context
+        let known_paths = self
+            .hunks
+            .iter()
+            .map(|hunk| hunk.path.clone())
+            .collect::<HashSet<_>>();
+        self.hunks.extend(
+            hunks
+                .into_iter()
+                .filter(|hunk| !known_paths.contains(&hunk.path)),
+        );
+    }
+}
+
+/// Scans the lines of a diff for added lines matching the search pattern.
+///
+/// Matching added lines are cancelled out by identical matching removed lines,
+/// so that lines which only moved aren't reported.
+///
+/// Working tree files with a `working-tree-encoding` attribute are decoded and
+/// diffed again, since libgit2 doesn't apply that attribute itself.
+pub fn scan(
+    repo: &git2::Repository,
+    diff: &git2::Diff<'_>,
+    options: &ScanOptions<'_>,
+) -> Result<Scan> {
+    let debug = DEBUG.load(Ordering::SeqCst);
+
+    let mut oversized_paths = HashSet::new();
+    if let Some(max_filesize) = options.max_filesize {
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                if file.size() <= max_filesize {
+                    continue;
+                }
+                if let Some(path) = file.path() {
+                    debug!(
+                        "skipping {} ({} bytes)",
+                        path.display(),
+                        file.size()
+                    );
+                    oversized_paths.insert(path.to_owned());
+                }
+            }
+        }
+    }
+
+    let mut usage = Usage::default();
+    // working tree files are the only ones whose blobs aren't in the object
+    // database, and the only ones which need decoding
+    let mut encoded_files = Vec::new();
+    for delta in diff.deltas() {
+        if delta.status() != git2::Delta::Unmodified {
+            usage.blobs_loaded += [delta.old_file(), delta.new_file()]
+                .iter()
+                .filter(|file| file.exists())
+                .count();
+        }
+        if matches!(
+            delta.status(),
+            git2::Delta::Unmodified | git2::Delta::Deleted
+        ) {
+            continue;
+        }
+        let new_file = delta.new_file();
+        let path = match new_file.path() {
+            Some(path) => path,
+            None => continue,
+        };
+        if repo.find_blob(new_file.id()).is_ok() {
+            continue;
+        }
+        if let Some(encoding) = encoding::working_tree_encoding(repo, path) {
+            debug!("decoding {} as {}", path.display(), encoding);
+            encoded_files.push((
+                path.to_owned(),
+                delta.old_file().id(),
+                encoding,
+            ));
+        }
+    }
+    let decoding = Cell::new(false);
+    let errors = RefCell::new(Vec::<(PathBuf, Error)>::new());
+    let record_error = |path: &Path, error: Error| {
+        debug!("skipping {}: {:#}", path.display(), error);
+        let mut errors = errors.borrow_mut();
+        if !errors.iter().any(|(p, _)| p == path) {
+            errors.push((path.to_owned(), error));
+        }
+    };
+
+    // added lines along with the ID of the text that removed lines cancel them
+    // out by, which for multiline matches is the whole match; only added lines
+    // matching the search are kept in full, and removed lines only by ID, so
+    // that searches matching every line, such as inverted or empty ones, take
+    // a fixed amount of memory for each removed line however long it is
+    let mut added_lines = Vec::<(git2::Oid, AddedLine)>::new();
+    let mut context_lines = Vec::new();
+    let mut current_hunk = None::<HunkText>;
+    let mut removed_lines = MultiSet::<git2::Oid>::new();
+    let mut touched_paths = HashMap::new();
+    let mut line_counts = HashMap::<PathBuf, (usize, usize)>::new();
+    let mut binary_paths = HashSet::new();
+    let mut hunks = Vec::<Hunk>::new();
+    let mut hunk_key = None;
+    // files with a matching added line, when only their paths are needed
+    let mut matched_paths = HashMap::<PathBuf, usize>::new();
+    let mut handle_line = |delta: git2::DiffDelta<'_>,
+                           hunk: Option<git2::DiffHunk<'_>>,
+                           line: git2::DiffLine<'_>|
+     -> Result<()> {
+        if !decoding.get() {
+            let new_path = delta.new_file().path();
+            if encoded_files
+                .iter()
+                .any(|(path, ..)| Some(path.as_path()) == new_path)
+            {
+                return Ok(());
+            }
+        }
+        let (added, context) = match line.origin_value() {
+            git2::DiffLineType::Addition => (true, false),
+            git2::DiffLineType::Deletion => (false, false),
+            git2::DiffLineType::Context => (false, true),
+            _ => return Ok(()),
+        };
+        usage.lines_scanned += 1;
+        usage.bytes_diffed += line.content().len() as u64;
+        let file = if added || context {
+            delta.new_file()
+        } else {
+            delta.old_file()
+        };
+        // files outside of the sparse checkout cone or otherwise missing from
+        // the working tree show up as deleted
+        if file.path().map_or(false, options.is_excluded) {
+            return Ok(());
+        }
+        if file
+            .path()
+            .map_or(false, |path| oversized_paths.contains(path))
+        {
+            return Ok(());
+        }
+        if let Some(diff_filter) = options.diff_filter {
+            if !diff_filter.matches(delta.status()) {
+                return Ok(());
+            }
+        }
+        if let Some(path) = file.path().filter(|_| !context) {
+            let (added_count, removed_count) =
+                if let Some(counts) = line_counts.get_mut(path) {
+                    counts
+                } else {
+                    line_counts.entry(path.to_owned()).or_default()
+                };
+            if added {
+                *added_count += 1;
+            } else {
+                *removed_count += 1;
+            }
+        }
+        if file.is_binary() {
+            return Ok(());
+        }
+        let search = match options.search {
+            Some(search) => search,
+            None => return Ok(()),
+        };
+        let raw_content = line.content();
+        let content = if options.binary != BinaryMode::Skip
+            && (raw_content.contains(&0)
+                || str::from_utf8(raw_content).is_err())
+        {
+            if let Some(path) = file.path() {
+                binary_paths.insert(path.to_owned());
+            }
+            String::from_utf8_lossy(raw_content)
+        } else {
+            match str::from_utf8(raw_content) {
+                Ok(content) => Cow::Borrowed(content),
+                Err(error) if options.keep_going => {
+                    if let Some(path) = file.path() {
+                        record_error(
+                            path,
+                            Error::new(error).context(
+                                "error converting line content to utf8",
+                            ),
+                        );
+                    }
+                    return Ok(());
+                },
+                Err(error) => {
+                    return Err(error)
+                        .context("error converting line content to utf8")
+                },
+            }
+        };
+        let content = content.trim();
+        // if the line is either added or deleted, one of these must be Some
+        let lineno = line
+            .new_lineno()
+            .or_else(|| line.old_lineno())
+            .expect("no lineno");
+        let path = match file.path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if added {
+            touched_paths
+                .entry(path.to_owned())
+                .or_insert_with(|| (file.id(), delta.status()));
+        }
+        if options.keep_hunks {
+            let key = (
+                path.to_owned(),
+                hunk.as_ref().map_or(0, |hunk| hunk.old_start()),
+                hunk.as_ref().map_or(0, |hunk| hunk.new_start()),
+            );
+            if hunk_key.as_ref() != Some(&key) {
+                hunks.push(Hunk {
+                    path: path.to_owned(),
+                    lines: Vec::new(),
+                });
+                hunk_key = Some(key);
+            }
+            let origin = match (added, context) {
+                (_, true) => ' ',
+                (true, _) => '+',
+                (false, _) => '-',
+            };
+            hunks.last_mut().expect("no current hunk").lines.push((
+                origin,
+                line.new_lineno(),
+                String::from_utf8_lossy(line.content())
+                    .trim_end()
+                    .to_owned(),
+            ));
+        }
+        if context && !options.match_context {
+            return Ok(());
+        }
+        if options.multiline && !context {
+            let key = (
+                path.to_owned(),
+                hunk.as_ref().map_or(0, |hunk| hunk.old_start()),
+                hunk.as_ref().map_or(0, |hunk| hunk.new_start()),
+            );
+            if current_hunk.as_ref().map(|hunk| &hunk.key) != Some(&key) {
+                if let Some(hunk) = current_hunk.take() {
+                    hunk.flush(search, &mut added_lines, &mut removed_lines);
+                }
+                current_hunk = Some(HunkText {
+                    key,
+                    status: delta.status(),
+                    added: Vec::new(),
+                    removed: Vec::new(),
+                });
+            }
+            let hunk = current_hunk.as_mut().expect("no current hunk");
+            if added {
+                hunk.added.push((lineno, content.to_owned()));
+            } else {
+                hunk.removed.push((lineno, content.to_owned()));
+            }
+            return Ok(());
+        }
+        if added && options.first_match_per_file {
+            if let Some(&idx) = matched_paths.get(path) {
+                added_lines
+                    .push((content_id(content), AddedLine::Unchecked(idx)));
+                return Ok(());
+            }
+        }
+        if let Some((ranges, rule)) =
+            find_match(search, content, options.invert)
+        {
+            if context {
+                // context lines weren't changed, so there's nothing for them
+                // to cancel out against
+                let line = Line {
+                    content: content.to_owned(),
+                    ranges,
+                    rule,
+                    lineno,
+                    path: path.to_owned(),
+                    kind: LineKind::Existing,
+                    status: delta.status(),
+                };
+                debug!("context line: {}", line);
+                context_lines.push(line);
+            } else if added {
+                let line = Line {
+                    content: content.to_owned(),
+                    ranges,
+                    rule,
+                    lineno,
+                    path: path.to_owned(),
+                    kind: LineKind::Added,
+                    status: delta.status(),
+                };
+                debug!("added line: {}", line);
+                if options.first_match_per_file {
+                    let idx = matched_paths.len();
+                    matched_paths.insert(path.to_owned(), idx);
+                }
+                added_lines
+                    .push((content_id(content), AddedLine::Matched(line)));
+            } else {
+                if debug {
+                    let line = Line {
+                        content: content.to_owned(),
+                        ranges,
+                        rule,
+                        lineno,
+                        path: path.to_owned(),
+                        kind: LineKind::Added,
+                        status: delta.status(),
+                    };
+                    debug!("removed line: {}", line);
+                }
+                removed_lines.insert(content_id(content));
+            }
+        }
+        Ok(())
+    };
+    process_diff(diff, git2::DiffFormat::Patch, &mut handle_line)
+        .context("error processing diff")?;
+
+    decoding.set(true);
+    for (path, old_id, encoding) in &encoded_files {
+        let (old_content, new_content) =
+            match read_encoded_file(repo, path, *old_id, encoding) {
+                Ok(Some(contents)) => contents,
+                Ok(None) => continue,
+                Err(error) if options.keep_going => {
+                    record_error(path, error);
+                    continue;
+                },
+                Err(error) => return Err(error),
+            };
+        let mut patch = git2::Patch::from_buffers(
+            &old_content,
+            Some(path),
+            new_content.as_bytes(),
+            Some(path),
+            Some(&mut diff_options()),
+        )
+        .context("error diffing decoded file")?;
+        process_patch(&mut patch, &mut handle_line)
+            .context("error processing decoded diff")?;
+    }
+    if let (Some(hunk), Some(search)) = (current_hunk, options.search) {
+        hunk.flush(search, &mut added_lines, &mut removed_lines);
+    }
+
+    let mut lines = Vec::new();
+    let mut cancelled_count = 0;
+    let mut unchecked_paths = HashSet::new();
+    for (id, added_line) in added_lines {
+        let cancelled = removed_lines.remove(&id);
+        match added_line {
+            AddedLine::Matched(line) if cancelled => {
+                debug!("filtering out added & removed line: {}", line);
+                cancelled_count += 1;
+            },
+            AddedLine::Matched(line) => lines.push(line),
+            // a removed line with the same content matched, so this would
+            // have too
+            AddedLine::Unchecked(_) if cancelled => cancelled_count += 1,
+            AddedLine::Unchecked(idx) => {
+                unchecked_paths.insert(idx);
+            },
+        }
+    }
+    if !context_lines.is_empty() {
+        lines.extend(context_lines);
+        lines.sort_by(|a, b| (&a.path, a.lineno).cmp(&(&b.path, b.lineno)));
+    }
+    if !unchecked_paths.is_empty() {
+        let listed_paths = lines
+            .iter()
+            .map(|line| line.path.as_path())
+            .collect::<HashSet<_>>();
+        let unsettled_path = matched_paths.iter().find(|(path, idx)| {
+            unchecked_paths.contains(*idx)
+                && !listed_paths.contains(path.as_path())
+        });
+        if let Some((path, _)) = unsettled_path {
+            // every match in the file was cancelled out, so whether it matches
+            // depends on the lines that were skipped
+            debug!(
+                "rescanning every line, since {} is unsettled",
+                path.display()
+            );
+            let mut scan = scan(
+                repo,
+                diff,
+                &ScanOptions {
+                    first_match_per_file: false,
+                    ..*options
+                },
+            )?;
+            scan.usage.add(usage);
+            return Ok(scan);
+        }
+    }
+    let mut binary_matches = Vec::new();
+    if options.binary == BinaryMode::Report {
+        lines.retain(|line| {
+            if !binary_paths.contains(&line.path) {
+                return true;
+            }
+            if !binary_matches.contains(&line.path) {
+                binary_matches.push(line.path.clone());
+            }
+            false
+        });
+    }
+
+    Ok(Scan {
+        lines,
+        touched_paths,
+        line_counts,
+        cancelled_count,
+        errors: errors.into_inner(),
+        binary_matches,
+        hunks,
+        usage,
+    })
+}
+
+/// The added and removed lines of a hunk, for matching across lines.
+struct HunkText {
+    /// the path and old and new start lines of the hunk
+    key: (PathBuf, u32, u32),
+    status: git2::Delta,
+    added: Vec<(u32, String)>,
+    removed: Vec<(u32, String)>,
+}
+
+impl HunkText {
+    /// Matches the added and removed lines of the hunk as a whole.
+    ///
+    /// Matches are reported on the line they start on, and are cancelled out
+    /// by the text they matched rather than by whole lines.
+    fn flush(
+        self,
+        search: &Patterns,
+        added_lines: &mut Vec<(git2::Oid, AddedLine)>,
+        removed_lines: &mut MultiSet<git2::Oid>,
+    ) {
+        let text = join_lines(&self.removed);
+        for (range, _, idx) in multiline_matches(search, &self.removed) {
+            debug!(
+                "removed multiline match at {}:{}: {}",
+                self.key.0.display(),
+                self.removed[idx].0,
+                &text[range.clone()]
+            );
+            removed_lines.insert(content_id(&text[range]));
+        }
+        let text = join_lines(&self.added);
+        let mut line_start = 0;
+        let mut line_starts = Vec::new();
+        for (_, content) in &self.added {
+            line_starts.push(line_start);
+            line_start += content.len() + 1;
+        }
+        for (range, rule, idx) in multiline_matches(search, &self.added) {
+            let (lineno, content) = &self.added[idx];
+            let start = range.start - line_starts[idx];
+            let end = (range.end - line_starts[idx]).min(content.len());
+            let line = Line {
+                content: content.clone(),
+                ranges: vec![start..end],
+                rule,
+                lineno: *lineno,
+                path: self.key.0.clone(),
+                kind: LineKind::Added,
+                status: self.status,
+            };
+            debug!("added multiline match: {}", line);
+            added_lines
+                .push((content_id(&text[range]), AddedLine::Matched(line)));
+        }
+    }
+}
+
+/// An added line, along with whether it matched.
+enum AddedLine {
+    Matched(Line),
+    /// a line which wasn't matched, since its file, by index, already had a
+    /// match and only file paths are needed
+    Unchecked(usize),
+}
+
+/// Identifies some text by its git blob ID, for cancelling added lines out
+/// by removed ones without keeping the removed lines' content.
+///
+/// Unlike the standard library's hashers, this is collision-resistant, so
+/// lines are never cancelled out by others which only hash the same.
+fn content_id(text: &str) -> git2::Oid {
+    git2::Oid::hash_object(git2::ObjectType::Blob, text.as_bytes())
+        .expect("error hashing line")
+}
+
+fn join_lines(lines: &[(u32, String)]) -> String {
+    lines
+        .iter()
+        .map(|(_, content)| content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Finds every match in some lines joined by newlines, along with the rule
+/// that matched and the index of the line each match starts on.
+fn multiline_matches(
+    search: &Patterns,
+    lines: &[(u32, String)],
+) -> Vec<(Range<usize>, usize, usize)> {
+    // patterns which match empty text would otherwise match a side of a hunk
+    // without any lines
+    if lines.is_empty() {
+        return Vec::new();
+    }
+    let text = join_lines(lines);
+    let mut line_ends = Vec::new();
+    let mut line_end = 0;
+    for (_, content) in lines {
+        line_end += content.len();
+        line_ends.push(line_end);
+        line_end += 1;
+    }
+    let mut matches = Vec::new();
+    let mut pos = 0;
+    while pos <= text.len() {
+        let (range, rule) = match search.find_at(&text, pos) {
+            Some(r#match) => r#match,
+            None => break,
+        };
+        let idx = line_ends.partition_point(|&end| end < range.start);
+        pos = if range.is_empty() {
+            // step past empty matches so the search makes progress
+            range.end
+                + text[range.end..].chars().next().map_or(1, char::len_utf8)
+        } else {
+            range.end
+        };
+        matches.push((range, rule, idx.min(lines.len() - 1)));
+    }
+    matches
+}
+
+/// Reads the old blob content and the decoded working tree content of a file
+/// with a `working-tree-encoding` attribute, or `None` if its encoding isn't
+/// supported.
+fn read_encoded_file(
+    repo: &git2::Repository,
+    path: &Path,
+    old_id: git2::Oid,
+    encoding: &str,
+) -> Result<Option<(Vec<u8>, String)>> {
+    let old_content = if old_id.is_zero() {
+        Vec::new()
+    } else {
+        repo.find_blob(old_id)
+            .context("error finding old blob")?
+            .content()
+            .to_owned()
+    };
+    let workdir = repo.workdir().context("repository has no workdir")?;
+    let new_content =
+        fs::read(workdir_path(workdir, path)).context("error reading file")?;
+    match encoding::decode(&new_content, encoding) {
+        Some(new_content) => Ok(Some((old_content, new_content))),
+        None => {
+            debug!("unsupported encoding {} for {}", encoding, path.display());
+            Ok(None)
+        },
+    }
+}
+
+fn process_patch<F>(patch: &mut git2::Patch<'_>, mut cb: F) -> Result<()>
+where
+    F: FnMut(
+        git2::DiffDelta<'_>,
+        Option<git2::DiffHunk<'_>>,
+        git2::DiffLine<'_>,
+    ) -> Result<()>,
+{
+    let mut cb_result = Ok(());
+    let print_result = patch
+        .print(&mut |delta, hunk, line| match cb(delta, hunk, line) {
+            Ok(()) => true,
+            Err(error) => {
+                cb_result = Err(error);
+                false
+            },
+        })
+        .context("error in iterating patch lines");
+    cb_result.and(print_result)
+}
+
+fn process_diff<F>(
+    diff: &git2::Diff<'_>,
+    format: git2::DiffFormat,
+    mut cb: F,
+) -> Result<()>
+where
+    F: FnMut(
+        git2::DiffDelta<'_>,
+        Option<git2::DiffHunk<'_>>,
+        git2::DiffLine<'_>,
+    ) -> Result<()>,
+{
+    let mut cb_result = Ok(());
+    let print_result = diff
+        .print(format, |delta, hunk, line| match cb(delta, hunk, line) {
+            Ok(()) => true,
+            Err(error) => {
+                cb_result = Err(error);
+                false
+            },
+        })
+        .context("error in iterating diff lines");
+    cb_result.and(print_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_uppercase_diff_filter_statuses() {
+        let filter = "AM".parse::<DiffFilter>().unwrap();
+        assert!(filter.matches(git2::Delta::Added));
+        assert!(filter.matches(git2::Delta::Modified));
+        assert!(!filter.matches(git2::Delta::Deleted));
+        assert!(!filter.matches(git2::Delta::Renamed));
+    }
+
+    #[test]
+    fn excludes_lowercase_diff_filter_statuses() {
+        let filter = "dr".parse::<DiffFilter>().unwrap();
+        assert!(filter.matches(git2::Delta::Modified));
+        assert!(filter.matches(git2::Delta::Added));
+        assert!(!filter.matches(git2::Delta::Deleted));
+        assert!(!filter.matches(git2::Delta::Renamed));
+    }
+
+    #[test]
+    fn treats_untracked_files_as_added() {
+        let filter = "A".parse::<DiffFilter>().unwrap();
+        assert!(filter.matches(git2::Delta::Untracked));
+        let filter = "a".parse::<DiffFilter>().unwrap();
+        assert!(!filter.matches(git2::Delta::Untracked));
+    }
+
+    #[test]
+    fn applies_exclusions_over_inclusions() {
+        let filter = "Mm".parse::<DiffFilter>().unwrap();
+        assert!(!filter.matches(git2::Delta::Modified));
+        let filter = "AMd".parse::<DiffFilter>().unwrap();
+        assert!(filter.matches(git2::Delta::Added));
+        assert!(!filter.matches(git2::Delta::Deleted));
+        assert!(!filter.matches(git2::Delta::Copied));
+    }
+
+    #[test]
+    fn matches_everything_with_an_empty_diff_filter() {
+        let filter = "".parse::<DiffFilter>().unwrap();
+        assert!(filter.matches(git2::Delta::Added));
+        assert!(filter.matches(git2::Delta::Deleted));
+    }
+
+    #[test]
+    fn rejects_invalid_diff_filter_statuses() {
+        assert!("X".parse::<DiffFilter>().is_err());
+        assert!("A,M".parse::<DiffFilter>().is_err());
+        assert!("A M".parse::<DiffFilter>().is_err());
+        assert!("*".parse::<DiffFilter>().is_err());
+        assert!("é".parse::<DiffFilter>().is_err());
+    }
+
+    fn patterns(patterns: &[&str]) -> Patterns {
+        Patterns::new(
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Box::new(regex::Regex::new(pattern).unwrap()) as Box<_>
+                })
+                .collect(),
+        )
+    }
+
+    fn numbered(lines: &[&str]) -> Vec<(u32, String)> {
+        (1..)
+            .zip(lines.iter().map(|&line| line.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn matches_across_lines() {
+        let lines = numbered(&["let x = foo(", "bar);"]);
+        let text = join_lines(&lines);
+        let matches = multiline_matches(&patterns(&[r"foo\(\s*bar"]), &lines);
+        assert_eq!(matches, [(8..16, 0, 0)]);
+        assert_eq!(&text[8..16], "foo(\nbar");
+    }
+
+    #[test]
+    fn reports_the_line_each_match_starts_on() {
+        let lines = numbered(&["xa", "b", "", "ab"]);
+        let matches = multiline_matches(&patterns(&["a", "b"]), &lines);
+        assert_eq!(
+            matches,
+            [(1..2, 0, 0), (3..4, 1, 1), (6..7, 0, 3), (7..8, 1, 3)]
+        );
+    }
+
+    #[test]
+    fn reports_matches_of_line_separators_on_the_line_before() {
+        let lines = numbered(&["a", "b"]);
+        let matches = multiline_matches(&patterns(&[r"\n"]), &lines);
+        assert_eq!(matches, [(1..2, 0, 0)]);
+        // carriage returns left at the end of a line are matched as text
+        let lines = numbered(&["a\r", "b"]);
+        let matches = multiline_matches(&patterns(&[r"\r\n"]), &lines);
+        assert_eq!(matches, [(1..3, 0, 0)]);
+    }
+
+    #[test]
+    fn steps_past_empty_matches() {
+        let lines = numbered(&["é", ""]);
+        let matches = multiline_matches(&patterns(&["x*"]), &lines);
+        assert_eq!(matches, [(0..0, 0, 0), (2..2, 0, 0), (3..3, 0, 1)]);
+    }
+
+    #[test]
+    fn matches_nothing_without_lines() {
+        assert_eq!(multiline_matches(&patterns(&[""]), &[]), []);
+        assert_eq!(multiline_matches(&patterns(&["x"]), &numbered(&[""])), []);
+    }
+
+    #[test]
+    fn flushes_hunks_with_only_removed_lines() {
+        let hunk = HunkText {
+            key: (PathBuf::from("a.rs"), 1, 1),
+            status: git2::Delta::Modified,
+            added: Vec::new(),
+            removed: numbered(&["gone"]),
+        };
+        let mut added_lines = Vec::new();
+        let mut removed_lines = MultiSet::new();
+        hunk.flush(&patterns(&[""]), &mut added_lines, &mut removed_lines);
+        assert!(added_lines.is_empty());
+        assert_eq!(removed_lines.into_items().len(), 5);
+    }
+
+    #[test]
+    fn identifies_content_by_its_blob_id() {
+        assert_eq!(
+            content_id("").to_string(),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+        assert_eq!(content_id("TODO"), content_id("TODO"));
+        assert_ne!(content_id("TODO"), content_id("TODO "));
+    }
+
+    /// Scans the diff between two sets of files for TODO, giving the path
+    /// and line number of each match.
+    fn scan_files(
+        name: &str,
+        old: &[(&str, &str)],
+        new: &[(&str, &str)],
+        first_match_per_file: bool,
+    ) -> Vec<(String, u32)> {
+        let path = std::env::temp_dir().join(format!(
+            "git-branch-grep-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        let repo = git2::Repository::init_bare(&path).unwrap();
+        let tree = |files: &[(&str, &str)]| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            for (name, content) in files {
+                let id = repo.blob(content.as_bytes()).unwrap();
+                builder.insert(name, id, 0o100644).unwrap();
+            }
+            repo.find_tree(builder.write().unwrap()).unwrap()
+        };
+        let diff = repo
+            .diff_tree_to_tree(Some(&tree(old)), Some(&tree(new)), None)
+            .unwrap();
+        let search = patterns(&["TODO"]);
+        let scan = scan(
+            &repo,
+            &diff,
+            &ScanOptions {
+                search: Some(&search),
+                is_excluded: &|_| false,
+                invert: false,
+                multiline: false,
+                keep_going: false,
+                binary: BinaryMode::Skip,
+                max_filesize: None,
+                diff_filter: None,
+                match_context: false,
+                keep_hunks: false,
+                first_match_per_file,
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        scan.lines
+            .into_iter()
+            .map(|line| (line.path.display().to_string(), line.lineno))
+            .collect()
+    }
+
+    #[test]
+    fn stops_matching_a_file_after_its_first_match() {
+        let new = [("a", "TODO 1\nTODO 2\n"), ("b", "x\nTODO 3\nTODO 4\n")];
+        assert_eq!(
+            scan_files("first-match", &[], &new, false),
+            [
+                ("a".to_owned(), 1),
+                ("a".to_owned(), 2),
+                ("b".to_owned(), 2),
+                ("b".to_owned(), 3)
+            ]
+        );
+        assert_eq!(
+            scan_files("first-match", &[], &new, true),
+            [("a".to_owned(), 1), ("b".to_owned(), 2)]
+        );
+    }
+
+    #[test]
+    fn cancels_skipped_lines_like_matched_ones() {
+        // b's skipped line takes one of the moved lines, so c's is cancelled
+        // out by the other, just as without skipping
+        let old = [("a", "TODO moved\nTODO moved\n")];
+        let new = [("b", "TODO new\nTODO moved\n"), ("c", "TODO moved\n")];
+        assert_eq!(
+            scan_files("skipped-cancel", &old, &new, false),
+            [("b".to_owned(), 1)]
+        );
+        assert_eq!(
+            scan_files("skipped-cancel", &old, &new, true),
+            [("b".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn rescans_files_whose_first_match_is_cancelled() {
+        let old = [("a", "TODO moved\n")];
+        let new = [("b", "TODO moved\nTODO new\n")];
+        assert_eq!(
+            scan_files("rescan", &old, &new, true),
+            [("b".to_owned(), 2)]
+        );
+    }
+}
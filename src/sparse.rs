@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use std::{collections::HashSet, fs, path::Path};
+
+/// The directories included by a cone-mode sparse checkout.
+#[derive(Debug)]
+pub struct SparseCone {
+    /// directories included along with everything beneath them
+    recursive: Vec<String>,
+    /// directories whose immediate files are included
+    parents: HashSet<String>,
+}
+
+impl SparseCone {
+    /// Loads the sparse checkout cone for a repository, if it has one.
+    ///
+    /// Sparse checkouts not using cone mode are ignored, since their patterns
+    /// can't be matched without a full gitignore implementation.
+    pub fn load(repo: &git2::Repository) -> Result<Option<Self>> {
+        let config = repo.config().context("error reading config")?;
+        let enabled = config.get_bool("core.sparseCheckout").unwrap_or(false);
+        let cone = config.get_bool("core.sparseCheckoutCone").unwrap_or(false);
+        if !enabled || !cone {
+            return Ok(None);
+        }
+        let patterns = fs::read_to_string(
+            repo.path().join("info").join("sparse-checkout"),
+        )
+        .context("error reading sparse checkout patterns")?;
+        Ok(Some(Self::parse(&patterns)))
+    }
+
+    fn parse(patterns: &str) -> Self {
+        let mut included = Vec::new();
+        // the root directory's files are always included in cone mode
+        let mut parents = HashSet::from([String::new()]);
+        for pattern in patterns.lines().map(str::trim) {
+            if let Some(dir) = pattern
+                .strip_prefix("!/")
+                .and_then(|pattern| pattern.strip_suffix("/*/"))
+            {
+                parents.insert(dir.to_owned());
+            } else if let Some(dir) = pattern
+                .strip_prefix('/')
+                .and_then(|pattern| pattern.strip_suffix('/'))
+            {
+                included.push(dir.to_owned());
+            }
+        }
+        let recursive = included
+            .into_iter()
+            .filter(|dir| !parents.contains(dir))
+            .collect();
+        Self { recursive, parents }
+    }
+
+    /// Checks whether a repository-relative path is inside the cone.
+    pub fn contains(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        self.parents.contains(parent)
+            || self.recursive.iter().any(|dir| {
+                path.strip_prefix(dir.as_str())
+                    .map_or(false, |rest| rest.starts_with('/'))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: &str = "/*\n!/*/\n/src/\n!/src/*/\n/src/app/\n/docs/\n";
+
+    #[test]
+    fn includes_only_root_files_without_patterns() {
+        let cone = SparseCone::parse("");
+        assert!(cone.contains(Path::new("README.md")));
+        assert!(!cone.contains(Path::new("src/main.rs")));
+    }
+
+    #[test]
+    fn includes_immediate_files_of_parents() {
+        let cone = SparseCone::parse(PATTERNS);
+        assert!(cone.contains(Path::new("README.md")));
+        assert!(cone.contains(Path::new("src/main.rs")));
+        assert!(!cone.contains(Path::new("src/lib/mod.rs")));
+        assert!(!cone.contains(Path::new("vendor/lib.rs")));
+    }
+
+    #[test]
+    fn includes_recursive_directories() {
+        let cone = SparseCone::parse(PATTERNS);
+        assert!(cone.contains(Path::new("src/app/main.rs")));
+        assert!(cone.contains(Path::new("src/app/a/b/c.rs")));
+        assert!(cone.contains(Path::new("docs/guide/intro.md")));
+        // a directory's name being a prefix of another's doesn't include it
+        assert!(!cone.contains(Path::new("src/apple/main.rs")));
+        assert!(!cone.contains(Path::new("docsite/index.md")));
+    }
+
+    #[test]
+    fn parses_crlf_and_padded_patterns() {
+        let cone = SparseCone::parse("/*\r\n!/*/\r\n  /src/  \r\n");
+        assert!(cone.contains(Path::new("src/a/b.rs")));
+        assert!(!cone.contains(Path::new("lib/a.rs")));
+    }
+
+    #[test]
+    fn ignores_patterns_outside_of_cone_mode() {
+        let cone = SparseCone::parse("# comment\n*.rs\nsrc/\n/src\n");
+        assert!(!cone.contains(Path::new("src/main.rs")));
+        assert!(!cone.contains(Path::new("lib/a.rs")));
+    }
+}
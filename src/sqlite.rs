@@ -0,0 +1,143 @@
+use crate::Line;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    tool_version TEXT NOT NULL,
+    base_commit TEXT NOT NULL,
+    head_commit TEXT
+);
+CREATE TABLE IF NOT EXISTS run_bases (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    position INTEGER NOT NULL,
+    base_commit TEXT NOT NULL,
+    PRIMARY KEY (run_id, position)
+);
+CREATE TABLE IF NOT EXISTS rules (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    pattern TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    path TEXT NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS matches (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    rule_id INTEGER NOT NULL REFERENCES rules(id),
+    file_id INTEGER NOT NULL REFERENCES files(id),
+    lineno INTEGER NOT NULL,
+    match_start INTEGER NOT NULL,
+    match_end INTEGER NOT NULL,
+    content TEXT NOT NULL
+);
+";
+
+/// Metadata about a single run of the tool.
+pub struct Run<'a> {
+    /// the diff bases, with the empty tree for an empty base
+    pub base_commits: &'a [git2::Oid],
+    pub head_commit: Option<git2::Oid>,
+    pub rules: &'a [String],
+}
+
+/// Appends a run and its matching lines to the database at the given path,
+/// creating it if needed.
+pub fn export(path: &Path, run: &Run<'_>, lines: &[Line]) -> Result<()> {
+    let mut conn =
+        Connection::open(path).context("error opening sqlite database")?;
+    conn.execute_batch(SCHEMA)
+        .context("error creating sqlite schema")?;
+
+    let tx = conn.transaction()?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    tx.execute(
+        "INSERT INTO runs (timestamp, tool_version, base_commit, head_commit)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+            timestamp as i64,
+            env!("CARGO_PKG_VERSION"),
+            run.base_commits[0].to_string(),
+            run.head_commit.map(|id| id.to_string()),
+        ],
+    )
+    .context("error inserting run")?;
+    let run_id = tx.last_insert_rowid();
+
+    // runs.base_commit only holds the first base, so every base of runs with
+    // several goes here
+    for (position, base_commit) in run.base_commits.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO run_bases (run_id, position, base_commit)
+             VALUES (?1, ?2, ?3)",
+            params![run_id, position as i64, base_commit.to_string()],
+        )
+        .context("error inserting run base")?;
+    }
+
+    let mut rule_ids = Vec::new();
+    for rule in run.rules {
+        tx.execute(
+            "INSERT INTO rules (run_id, pattern) VALUES (?1, ?2)",
+            params![run_id, rule],
+        )
+        .context("error inserting rule")?;
+        rule_ids.push(tx.last_insert_rowid());
+    }
+
+    let mut file_ids = HashMap::new();
+    for line in lines {
+        let file_id = match file_ids.get(&line.path) {
+            Some(&file_id) => file_id,
+            None => {
+                tx.execute(
+                    "INSERT INTO files (run_id, path, status)
+                     VALUES (?1, ?2, ?3)",
+                    params![
+                        run_id,
+                        line.path.to_string_lossy().into_owned(),
+                        format!("{:?}", line.status).to_lowercase(),
+                    ],
+                )
+                .context("error inserting file")?;
+                let file_id = tx.last_insert_rowid();
+                file_ids.insert(&line.path, file_id);
+                file_id
+            },
+        };
+        tx.execute(
+            "INSERT INTO matches (
+                run_id, rule_id, file_id, lineno, match_start, match_end,
+                content
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                run_id,
+                rule_ids[line.rule],
+                file_id,
+                line.lineno,
+                line.range().start as i64,
+                line.range().end as i64,
+                line.content,
+            ],
+        )
+        .context("error inserting match")?;
+    }
+
+    tx.commit().context("error committing sqlite transaction")?;
+    Ok(())
+}
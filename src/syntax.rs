@@ -0,0 +1,342 @@
+use std::{ops::Range, path::Path};
+
+/// A kind of token picked out for syntax highlighting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+impl Token {
+    /// The CSS class the HTML report styles this kind of token with.
+    pub fn class(self) -> &'static str {
+        match self {
+            Self::Keyword => "kw",
+            Self::String => "str",
+            Self::Number => "num",
+            Self::Comment => "com",
+        }
+    }
+}
+
+/// Enough of a language's lexical rules to highlight a single line.
+///
+/// Lines are highlighted on their own, so comments and strings which started
+/// on an earlier line aren't recognized.
+pub struct Syntax {
+    extensions: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    keywords: &'static [&'static str],
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "break",
+    "case",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "for",
+    "if",
+    "namespace",
+    "new",
+    "null",
+    "nullptr",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "struct",
+    "switch",
+    "this",
+    "true",
+    "typedef",
+    "union",
+    "using",
+    "void",
+    "while",
+];
+
+const SYNTAXES: &[Syntax] = &[
+    Syntax {
+        extensions: &["rs"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate",
+            "dyn", "else", "enum", "false", "fn", "for", "if", "impl", "in",
+            "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+            "return", "self", "Self", "static", "struct", "super", "trait",
+            "true", "type", "unsafe", "use", "where", "while",
+        ],
+    },
+    Syntax {
+        extensions: &[
+            "c", "h", "cpp", "cc", "cxx", "hpp", "hh", "hxx", "cs", "java",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        keywords: C_KEYWORDS,
+    },
+    Syntax {
+        extensions: &[
+            "js", "jsx", "mjs", "cjs", "ts", "tsx", "mts", "cts", "kt", "kts",
+            "swift", "go", "php",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "do",
+            "else",
+            "enum",
+            "export",
+            "extends",
+            "false",
+            "finally",
+            "for",
+            "func",
+            "function",
+            "fun",
+            "go",
+            "if",
+            "import",
+            "in",
+            "interface",
+            "let",
+            "new",
+            "nil",
+            "null",
+            "package",
+            "return",
+            "static",
+            "struct",
+            "switch",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "type",
+            "undefined",
+            "val",
+            "var",
+            "while",
+            "yield",
+        ],
+    },
+    Syntax {
+        extensions: &["py", "pyi"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        keywords: &[
+            "and", "as", "async", "await", "break", "class", "continue", "def",
+            "elif", "else", "except", "False", "finally", "for", "from", "if",
+            "import", "in", "is", "lambda", "None", "not", "or", "pass",
+            "raise", "return", "True", "try", "while", "with", "yield",
+        ],
+    },
+    Syntax {
+        extensions: &["rb"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        keywords: &[
+            "begin", "class", "def", "do", "else", "elsif", "end", "false",
+            "if", "module", "nil", "require", "rescue", "return", "self",
+            "true", "unless", "until", "while", "yield",
+        ],
+    },
+    Syntax {
+        extensions: &["sh", "bash", "zsh"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi",
+            "for", "function", "if", "in", "local", "return", "then", "while",
+        ],
+    },
+    Syntax {
+        extensions: &["sql"],
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['\''],
+        keywords: &[
+            "and", "as", "by", "create", "delete", "from", "group", "insert",
+            "into", "join", "not", "null", "on", "or", "order", "select",
+            "set", "table", "update", "values", "where", "AND", "AS", "BY",
+            "CREATE", "DELETE", "FROM", "GROUP", "INSERT", "INTO", "JOIN",
+            "NOT", "NULL", "ON", "OR", "ORDER", "SELECT", "SET", "TABLE",
+            "UPDATE", "VALUES", "WHERE",
+        ],
+    },
+    Syntax {
+        extensions: &["toml", "yaml", "yml"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        keywords: &["false", "true"],
+    },
+];
+
+impl Syntax {
+    /// Picks the syntax for a file by its extension.
+    pub fn for_path(path: &Path) -> Option<&'static Self> {
+        let extension = path.extension()?.to_str()?;
+        SYNTAXES
+            .iter()
+            .find(|syntax| syntax.extensions.contains(&extension))
+    }
+
+    /// Finds the tokens in a line worth highlighting, in order.
+    pub fn tokenize(&self, line: &str) -> Vec<(Range<usize>, Token)> {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let mut tokens = Vec::new();
+        let mut idx = 0;
+        while let Some(c) = line[idx..].chars().next() {
+            let rest = &line[idx..];
+            if self
+                .line_comments
+                .iter()
+                .any(|comment| rest.starts_with(comment))
+            {
+                tokens.push((idx..line.len(), Token::Comment));
+                break;
+            }
+            if let Some((open, close)) = self.block_comment {
+                if let Some(comment) = rest.strip_prefix(open) {
+                    let end = comment.find(close).map_or(line.len(), |end| {
+                        idx + open.len() + end + close.len()
+                    });
+                    tokens.push((idx..end, Token::Comment));
+                    idx = end;
+                    continue;
+                }
+            }
+            if self.quotes.contains(&c) {
+                let mut end = line.len();
+                let mut escaped = false;
+                for (offset, d) in rest.char_indices().skip(1) {
+                    if escaped {
+                        escaped = false;
+                    } else if d == '\\' {
+                        escaped = true;
+                    } else if d == c {
+                        end = idx + offset + d.len_utf8();
+                        break;
+                    }
+                }
+                tokens.push((idx..end, Token::String));
+                idx = end;
+                continue;
+            }
+            if is_word(c) {
+                // dots only continue numbers, such as 1.5
+                let number = c.is_ascii_digit();
+                let end = rest
+                    .find(|d: char| !(is_word(d) || number && d == '.'))
+                    .map_or(line.len(), |end| idx + end);
+                if number {
+                    tokens.push((idx..end, Token::Number));
+                } else if self.keywords.contains(&&line[idx..end]) {
+                    tokens.push((idx..end, Token::Keyword));
+                }
+                idx = end;
+                continue;
+            }
+            idx += c.len_utf8();
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(path: &str, line: &str) -> Vec<(&'static str, String)> {
+        Syntax::for_path(Path::new(path))
+            .unwrap()
+            .tokenize(line)
+            .into_iter()
+            .map(|(range, token)| (token.class(), line[range].to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn picks_syntax_by_extension() {
+        assert!(Syntax::for_path(Path::new("src/main.rs")).is_some());
+        assert!(Syntax::for_path(Path::new("a/b.tsx")).is_some());
+        assert!(Syntax::for_path(Path::new("README")).is_none());
+        assert!(Syntax::for_path(Path::new("notes.txt")).is_none());
+    }
+
+    #[test]
+    fn tokenizes_keywords_strings_numbers_and_comments() {
+        assert_eq!(
+            tokens("a.rs", r#"let x = "hi \" there" + 1.5; // TODO"#),
+            [
+                ("kw", "let".to_owned()),
+                ("str", r#""hi \" there""#.to_owned()),
+                ("num", "1.5".to_owned()),
+                ("com", "// TODO".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_keywords_inside_words() {
+        assert_eq!(tokens("a.py", "iffy = format_if"), []);
+        assert_eq!(tokens("a.py", "x.if_"), []);
+    }
+
+    #[test]
+    fn ends_unterminated_tokens_at_end_of_line() {
+        assert_eq!(tokens("a.js", "x = 'abc"), [("str", "'abc".to_owned())]);
+        assert_eq!(
+            tokens("a.c", "f(); /* TODO"),
+            [("com", "/* TODO".to_owned())]
+        );
+    }
+
+    #[test]
+    fn closes_block_comments_within_a_line() {
+        assert_eq!(
+            tokens("a.c", "/* a */ return 0;"),
+            [
+                ("com", "/* a */".to_owned()),
+                ("kw", "return".to_owned()),
+                ("num", "0".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_empty_and_non_ascii_lines() {
+        assert_eq!(tokens("a.rs", ""), []);
+        assert_eq!(tokens("a.rs", "é = \"ü\""), [("str", "\"ü\"".to_owned())]);
+    }
+}